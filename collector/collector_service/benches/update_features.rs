@@ -0,0 +1,90 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use collector_service::{Fuzzer, Target};
+use common::collector_proto::{
+    control_flow_graph::{BasicBlock, Function},
+    ControlFlowGraph,
+};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use std::sync::Arc;
+
+const NUM_FUNCTIONS: u64 = 10000;
+const FEATURES_PER_UPDATE: u64 = 4096;
+
+// Functions made of an instrumented entry, a diamond of one instrumented and
+// one uninstrumented branch, and an instrumented exit.
+fn build_cfg() -> ControlFlowGraph {
+    let mut functions = Vec::new();
+    for function_index in 0..NUM_FUNCTIONS {
+        let first_block_id = function_index * 4;
+        let first_sancov_index = function_index * 3;
+        let block = |offset: u64, successors: Vec<u64>, sancov_index: Option<u64>| BasicBlock {
+            id: first_block_id + offset,
+            successors: successors
+                .into_iter()
+                .map(|successor| first_block_id + successor)
+                .collect(),
+            sancov_index: sancov_index.map_or(common::NO_SANCOV_INDEX, |sancov_index| {
+                first_sancov_index + sancov_index
+            }),
+        };
+        functions.push(Function {
+            id: function_index,
+            name: format!("function_{}", function_index),
+            basic_blocks: vec![
+                block(0, vec![1, 2], Some(0)),
+                block(1, vec![3], Some(1)),
+                block(2, vec![3], None),
+                block(3, vec![], Some(2)),
+            ],
+        });
+    }
+    ControlFlowGraph { functions }
+}
+
+fn bench_update_features(c: &mut Criterion) {
+    let target = Arc::new(Target::from_cfg(&build_cfg()).unwrap());
+    // Spread the features over the whole target, with varied hit count buckets.
+    let features: Vec<u32> = (0..FEATURES_PER_UPDATE)
+        .map(|index| {
+            let sancov_index = index * 7919 % (NUM_FUNCTIONS * 3);
+            (sancov_index * 8 + index % 8) as u32
+        })
+        .collect();
+
+    let mut group = c.benchmark_group("update_features");
+    group.throughput(Throughput::Elements(FEATURES_PER_UPDATE));
+    group.bench_function("fresh_fuzzer", |b| {
+        b.iter_batched(
+            || Fuzzer::new(target.clone(), None),
+            |mut fuzzer| {
+                let features = fuzzer.decode_features(&features);
+                fuzzer.update_features(&features)
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("warm_fuzzer", |b| {
+        let mut fuzzer = Fuzzer::new(target.clone(), None);
+        b.iter(|| {
+            let features = fuzzer.decode_features(&features);
+            fuzzer.update_features(&features)
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_update_features);
+criterion_main!(benches);
@@ -0,0 +1,71 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use common::observer_proto::HitCountBucket;
+use std::ops::{BitOr, BitOrAssign};
+
+// Number of hit count buckets, one bit each in the counter.
+const NUM_BUCKETS: u8 = 8;
+
+/// Hit count buckets observed on a node, as a bitmask with bit i set for
+/// `HitCountBucket` i.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct BitCounter(u8);
+
+impl BitCounter {
+    pub fn new(bits: u8) -> Self {
+        Self(bits)
+    }
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn contains(self, bucket: HitCountBucket) -> bool {
+        self.0 & (1 << bucket as u8) != 0
+    }
+
+    /// Observed buckets, from the lowest hit count to the highest.
+    pub fn buckets(self) -> impl Iterator<Item = HitCountBucket> {
+        (0..NUM_BUCKETS)
+            .filter(move |bit| self.0 & (1 << bit) != 0)
+            .filter_map(|bit| HitCountBucket::from_i32(bit as i32))
+    }
+
+    /// Highest observed bucket, e.g. the most loop iterations seen on the node.
+    pub fn max_bucket(self) -> Option<HitCountBucket> {
+        match self.0 {
+            0 => None,
+            bits => HitCountBucket::from_i32(7 - bits.leading_zeros() as i32),
+        }
+    }
+}
+
+impl BitOr for BitCounter {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for BitCounter {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
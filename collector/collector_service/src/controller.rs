@@ -0,0 +1,168 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{corpus::Corpus, error::Error, fuzzer_map::FuzzerMap};
+use common::{
+    collector_proto::{control_command::Command, ControlCommand},
+    observer_proto::FuzzerStats,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::mpsc;
+
+type CommandSender = mpsc::Sender<ControlCommand>;
+
+// Commands beyond this many pending for a fuzzer are dropped, so a fuzzer which
+// doesn't read its control stream can't make the collector buffer every seed
+// redistributed to it.
+const CONTROL_CHANNEL_CAPACITY: usize = 256;
+
+/// Handle to push commands to fuzzers through their control channels, and to
+/// read the seeds and stats they reported.
+///
+/// It is cheap to clone, so observers can keep a copy and send commands at any
+/// time.
+#[derive(Clone, Default)]
+pub struct Controller {
+    command_senders: Arc<Mutex<HashMap<u64, CommandSender>>>,
+    corpus: Corpus,
+    // Shared with the service the controller is passed to.
+    fuzzer_map: Arc<FuzzerMap>,
+}
+
+impl Controller {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn corpus(&self) -> &Corpus {
+        &self.corpus
+    }
+
+    /// Returns the latest stats reported by the fuzzer, or None if it's not
+    /// registered.
+    pub fn fuzzer_stats(&self, fuzzer_id: u64) -> Option<FuzzerStats> {
+        let entry = self.fuzzer_map.get(fuzzer_id)?;
+        let stats = entry.stats.lock().unwrap().clone();
+        Some(stats)
+    }
+
+    pub(crate) fn fuzzer_map(&self) -> &Arc<FuzzerMap> {
+        &self.fuzzer_map
+    }
+
+    /// Sends a command to the fuzzer. Returns false if the fuzzer has no open
+    /// control channel, or the command is dropped as too many are pending.
+    pub fn send_command(&self, fuzzer_id: u64, command: Command) -> bool {
+        match self.command_senders.lock().unwrap().get(&fuzzer_id) {
+            Some(sender) => sender
+                .try_send(ControlCommand {
+                    command: Some(command),
+                })
+                .is_ok(),
+            None => false,
+        }
+    }
+
+    /// Opens the control channel of the fuzzer. Fails if it already has one,
+    /// unless that one's stream has been dropped without unregistering yet.
+    pub(crate) fn register(
+        &self,
+        fuzzer_id: u64,
+    ) -> Result<(CommandSender, mpsc::Receiver<ControlCommand>), Error> {
+        let mut command_senders = self.command_senders.lock().unwrap();
+        if let Some(registered_sender) = command_senders.get(&fuzzer_id) {
+            if !registered_sender.is_closed() {
+                return Err(Error::DuplicateControlStream(fuzzer_id));
+            }
+        }
+        let (sender, receiver) = mpsc::channel(CONTROL_CHANNEL_CAPACITY);
+        command_senders.insert(fuzzer_id, sender.clone());
+        Ok((sender, receiver))
+    }
+
+    pub(crate) fn unregister(&self, fuzzer_id: u64, sender: &CommandSender) {
+        let mut command_senders = self.command_senders.lock().unwrap();
+        // The fuzzer may have reopened its channel before the old one closed.
+        if let Some(registered_sender) = command_senders.get(&fuzzer_id) {
+            if registered_sender.same_channel(sender) {
+                command_senders.remove(&fuzzer_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fuzzer::{Fuzzer, Target};
+    use common::collector_proto::ControlFlowGraph;
+
+    #[test]
+    fn register_rejects_second_open_stream() {
+        let controller = Controller::new();
+        let (sender, receiver) = controller.register(1).unwrap();
+        assert!(matches!(
+            controller.register(1),
+            Err(Error::DuplicateControlStream(1))
+        ));
+        controller.register(2).unwrap();
+
+        // A dropped stream is replaced even before it's unregistered.
+        drop(receiver);
+        let (_, mut receiver) = controller.register(1).unwrap();
+        controller.unregister(1, &sender);
+        assert!(controller.send_command(1, Command::AddSeeds(Default::default())));
+        assert!(receiver.try_recv().is_ok());
+    }
+
+    #[test]
+    fn send_command_drops_beyond_capacity() {
+        let controller = Controller::new();
+        let (_sender, mut receiver) = controller.register(1).unwrap();
+        for _ in 0..CONTROL_CHANNEL_CAPACITY {
+            assert!(controller.send_command(1, Command::AddSeeds(Default::default())));
+        }
+        assert!(!controller.send_command(1, Command::AddSeeds(Default::default())));
+        receiver.try_recv().unwrap();
+        assert!(controller.send_command(1, Command::AddSeeds(Default::default())));
+    }
+
+    #[test]
+    fn fuzzer_stats_follow_the_fuzzer_map() {
+        let controller = Controller::new();
+        let target = Arc::new(Target::from_cfg(&ControlFlowGraph::default()).unwrap());
+        controller
+            .fuzzer_map()
+            .insert(1, Fuzzer::new(target, None), 1);
+        assert_eq!(controller.fuzzer_stats(1), Some(FuzzerStats::default()));
+
+        let stats = FuzzerStats {
+            total_execs: 1000,
+            ..Default::default()
+        };
+        *controller
+            .fuzzer_map()
+            .get(1)
+            .unwrap()
+            .stats
+            .lock()
+            .unwrap() = stats.clone();
+        assert_eq!(controller.fuzzer_stats(1), Some(stats));
+        controller.fuzzer_map().remove(1);
+        assert_eq!(controller.fuzzer_stats(1), None);
+    }
+}
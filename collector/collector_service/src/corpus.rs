@@ -0,0 +1,151 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+// Seeds beyond these are rejected, so a fuzzer can't fill the corpus with a
+// few large inputs.
+const MAX_SEED_SIZE: usize = 1 << 20;
+// The oldest seeds are evicted to stay within these, so a long campaign
+// doesn't grow the corpus without bound.
+const MAX_CORPUS_SEEDS: usize = 1 << 20;
+const MAX_CORPUS_BYTES: usize = 1 << 30;
+
+#[derive(Default)]
+struct Seeds {
+    seeds: HashMap<Vec<u8>, Arc<Vec<u8>>>,
+    // SHA1s in insertion order, so the oldest seed is evicted first.
+    sha1s: VecDeque<Vec<u8>>,
+    num_bytes: usize,
+}
+
+/// Content-addressed store of the seeds found by all fuzzers, keyed by their
+/// SHA1. It keeps the latest seeds up to a size limit.
+#[derive(Clone)]
+pub struct Corpus {
+    seeds: Arc<Mutex<Seeds>>,
+    max_seed_size: usize,
+    max_seeds: usize,
+    max_bytes: usize,
+}
+
+impl Default for Corpus {
+    fn default() -> Self {
+        Self::with_limits(MAX_SEED_SIZE, MAX_CORPUS_SEEDS, MAX_CORPUS_BYTES)
+    }
+}
+
+impl Corpus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn with_limits(max_seed_size: usize, max_seeds: usize, max_bytes: usize) -> Self {
+        Self {
+            seeds: Arc::new(Mutex::new(Seeds::default())),
+            max_seed_size,
+            max_seeds,
+            max_bytes,
+        }
+    }
+
+    pub fn get(&self, sha1: &[u8]) -> Option<Arc<Vec<u8>>> {
+        self.seeds.lock().unwrap().seeds.get(sha1).cloned()
+    }
+
+    pub fn contains(&self, sha1: &[u8]) -> bool {
+        self.seeds.lock().unwrap().seeds.contains_key(sha1)
+    }
+
+    pub fn len(&self) -> usize {
+        self.seeds.lock().unwrap().seeds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the total size of the seeds in the corpus.
+    pub fn num_bytes(&self) -> usize {
+        self.seeds.lock().unwrap().num_bytes
+    }
+
+    /// Returns the SHA1 of every seed in the corpus, oldest first.
+    pub fn sha1s(&self) -> Vec<Vec<u8>> {
+        self.seeds.lock().unwrap().sha1s.iter().cloned().collect()
+    }
+
+    /// Returns false if the seed was already in the corpus, or is too large.
+    /// The oldest seeds are evicted to make room for it.
+    pub(crate) fn insert(&self, sha1: Vec<u8>, seed: Vec<u8>) -> bool {
+        if seed.len() > self.max_seed_size {
+            return false;
+        }
+        let mut seeds = self.seeds.lock().unwrap();
+        if seeds.seeds.contains_key(&sha1) {
+            return false;
+        }
+        while seeds.seeds.len() >= self.max_seeds || seeds.num_bytes + seed.len() > self.max_bytes {
+            let evicted_sha1 = match seeds.sha1s.pop_front() {
+                Some(evicted_sha1) => evicted_sha1,
+                None => break,
+            };
+            if let Some(evicted_seed) = seeds.seeds.remove(&evicted_sha1) {
+                seeds.num_bytes -= evicted_seed.len();
+            }
+        }
+        seeds.num_bytes += seed.len();
+        seeds.sha1s.push_back(sha1.clone());
+        seeds.seeds.insert(sha1, Arc::new(seed));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_oversized_seeds() {
+        let corpus = Corpus::with_limits(4, 8, 64);
+        assert!(!corpus.insert(b"large".to_vec(), vec![0; 5]));
+        assert!(corpus.insert(b"small".to_vec(), vec![0; 4]));
+        assert!(!corpus.insert(b"small".to_vec(), vec![0; 4]));
+        assert_eq!(corpus.sha1s(), [b"small"]);
+    }
+
+    #[test]
+    fn evicts_oldest_seeds() {
+        let corpus = Corpus::with_limits(6, 3, 8);
+        for sha1 in [b"a", b"b", b"c"].iter() {
+            assert!(corpus.insert(sha1.to_vec(), vec![0; 2]));
+        }
+        // Beyond the number of seeds.
+        assert!(corpus.insert(b"d".to_vec(), vec![0; 2]));
+        assert_eq!(corpus.sha1s(), [b"b", b"c", b"d"]);
+        assert!(corpus.get(b"a").is_none());
+
+        // Beyond the bytes, which takes two evictions.
+        assert!(corpus.insert(b"e".to_vec(), vec![0; 6]));
+        assert_eq!(corpus.sha1s(), [b"d", b"e"]);
+        assert_eq!(corpus.num_bytes(), 8);
+
+        // An evicted seed can be added again.
+        assert!(corpus.insert(b"a".to_vec(), vec![0; 2]));
+        assert_eq!(corpus.sha1s(), [b"e", b"a"]);
+    }
+}
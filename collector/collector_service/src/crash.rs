@@ -0,0 +1,206 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use common::observer_proto::Crash;
+use sha2::{Digest, Sha256};
+
+// Number of frames from the top of the stack which identify a crash.
+const CRASH_STACK_FRAMES: usize = 3;
+// Frames of libFuzzer, the sanitizer runtimes and the signal handling, which
+// are on top of the stack of every crash. Prefixes are only used for names
+// reserved to the implementation, so user functions can't match them.
+const RUNTIME_FRAME_PREFIXES: &[&str] = &[
+    "fuzzer::",
+    "__sanitizer",
+    "__asan",
+    "__msan",
+    "__ubsan",
+    "__lsan",
+    "__interceptor_",
+    "__GI_",
+    "__restore_rt",
+    "__pthread_kill",
+    "__assert",
+];
+const RUNTIME_FRAME_NAMES: &[&str] = &["abort", "raise", "gsignal", "pthread_kill", "backtrace"];
+
+fn is_runtime_frame(frame: &str) -> bool {
+    // Demangled C++ names come with their parameters.
+    let name = frame.split('(').next().unwrap_or_default().trim();
+    RUNTIME_FRAME_PREFIXES
+        .iter()
+        .any(|prefix| name.starts_with(prefix))
+        || RUNTIME_FRAME_NAMES.contains(&name)
+}
+
+// Top frames of the crash below the runtime frames it was reported from.
+fn crash_stack_frames(crash: &Crash) -> impl Iterator<Item = &String> {
+    crash
+        .stack_frames
+        .iter()
+        .skip_while(|frame| is_runtime_frame(frame))
+        .take(CRASH_STACK_FRAMES)
+}
+
+/// Hashes the kind and top frames of the crash, so the same bug found by
+/// different fuzzers or inputs gets the same hash. Returns None if the crash
+/// has no frames below the runtime ones, e.g. leaks and OOMs found outside of
+/// the execution, since nothing tells those bugs apart.
+pub fn hash_crash_stack(crash: &Crash) -> Option<Vec<u8>> {
+    let mut frames = crash_stack_frames(crash).peekable();
+    frames.peek()?;
+    let mut hasher = Sha256::new();
+    hasher.update(crash.kind.as_bytes());
+    for frame in frames {
+        // Separates frames, so different splits of the same names don't collide.
+        hasher.update(b"\n");
+        hasher.update(frame.as_bytes());
+    }
+    Some(hasher.finalize().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_crash(stack_frames: &[&str]) -> Crash {
+        Crash {
+            kind: "crash".to_owned(),
+            stack_frames: stack_frames.iter().map(|frame| frame.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn top_frames(stack_frames: &[&str]) -> Vec<String> {
+        crash_stack_frames(&build_crash(stack_frames))
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn skips_asan_report_frames() {
+        let frames = top_frames(&[
+            "fuzzer::GetSymbolizedStackFrames()",
+            "fuzzer::Fuzzer::ReportCrashToFuzzerClient(char const*, unsigned char const*, unsigned long)",
+            "fuzzer::Fuzzer::DeathCallback()",
+            "__sanitizer::Die()",
+            "__asan::ScopedInErrorReport::~ScopedInErrorReport()",
+            "__asan::ReportGenericError(unsigned long, unsigned long, unsigned long, unsigned long, bool, unsigned long, unsigned int, bool)",
+            "__asan_report_load4",
+            "parse_header(unsigned char const*)",
+            "parse(unsigned char const*, unsigned long)",
+            "LLVMFuzzerTestOneInput",
+            "fuzzer::Fuzzer::ExecuteCallback(unsigned char const*, unsigned long)",
+        ]);
+        assert_eq!(
+            frames,
+            [
+                "parse_header(unsigned char const*)",
+                "parse(unsigned char const*, unsigned long)",
+                "LLVMFuzzerTestOneInput",
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_abort_signal_frames() {
+        let frames = top_frames(&[
+            "fuzzer::GetSymbolizedStackFrames()",
+            "fuzzer::Fuzzer::CrashCallback()",
+            "fuzzer::CrashHandler(int, siginfo_t*, void*)",
+            "__restore_rt",
+            "__pthread_kill_implementation",
+            "pthread_kill",
+            "raise",
+            "abort",
+            "__assert_fail_base",
+            "__assert_fail",
+            "abort_transaction(transaction*)",
+            "LLVMFuzzerTestOneInput",
+        ]);
+        assert_eq!(
+            frames,
+            ["abort_transaction(transaction*)", "LLVMFuzzerTestOneInput"]
+        );
+    }
+
+    #[test]
+    fn keeps_runtime_frames_below_user_frames() {
+        // Only the frames on top are skipped, so the hash still tells apart
+        // crashes whose stacks are mostly in the runtime.
+        let frames = top_frames(&[
+            "fuzzer::Fuzzer::CrashCallback()",
+            "__restore_rt",
+            "raise",
+            "LLVMFuzzerTestOneInput",
+            "fuzzer::Fuzzer::ExecuteCallback(unsigned char const*, unsigned long)",
+            "fuzzer::Fuzzer::RunOne(unsigned char const*, unsigned long, bool, fuzzer::InputInfo*, bool, bool*)",
+            "fuzzer::Fuzzer::MutateAndTestOne()",
+        ]);
+        assert_eq!(
+            frames,
+            [
+                "LLVMFuzzerTestOneInput",
+                "fuzzer::Fuzzer::ExecuteCallback(unsigned char const*, unsigned long)",
+                "fuzzer::Fuzzer::RunOne(unsigned char const*, unsigned long, bool, fuzzer::InputInfo*, bool, bool*)",
+            ]
+        );
+    }
+
+    #[test]
+    fn same_bug_gets_same_hash() {
+        let asan_crash = build_crash(&[
+            "fuzzer::Fuzzer::DeathCallback()",
+            "__sanitizer::Die()",
+            "__asan_report_store1",
+            "parse_header(unsigned char const*)",
+            "LLVMFuzzerTestOneInput",
+        ]);
+        let other_asan_crash = build_crash(&[
+            "fuzzer::Fuzzer::DeathCallback()",
+            "__sanitizer::Die()",
+            "__asan::ReportGenericError(unsigned long, unsigned long, unsigned long, unsigned long, bool, unsigned long, unsigned int, bool)",
+            "__asan_report_store1",
+            "parse_header(unsigned char const*)",
+            "LLVMFuzzerTestOneInput",
+        ]);
+        let abort_crash = build_crash(&[
+            "fuzzer::Fuzzer::CrashCallback()",
+            "raise",
+            "abort",
+            "parse_header(unsigned char const*)",
+            "LLVMFuzzerTestOneInput",
+        ]);
+        assert_eq!(
+            hash_crash_stack(&asan_crash),
+            hash_crash_stack(&other_asan_crash)
+        );
+        assert_eq!(
+            hash_crash_stack(&asan_crash),
+            hash_crash_stack(&abort_crash)
+        );
+        let mut timeout = asan_crash.clone();
+        timeout.kind = "timeout".to_owned();
+        assert_ne!(hash_crash_stack(&asan_crash), hash_crash_stack(&timeout));
+    }
+
+    #[test]
+    fn crashes_without_frames_have_no_hash() {
+        let mut leak = build_crash(&[]);
+        leak.kind = "leak".to_owned();
+        assert_eq!(hash_crash_stack(&leak), None);
+        let runtime_only = build_crash(&["fuzzer::Fuzzer::DeathCallback()", "__sanitizer::Die()"]);
+        assert_eq!(hash_crash_stack(&runtime_only), None);
+    }
+}
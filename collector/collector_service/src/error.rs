@@ -0,0 +1,108 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt;
+use tonic::Status;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    MissingCfg,
+    /// The CFG isn't in the store and needs to be uploaded.
+    UnknownCfg,
+    /// The CFG is still being analyzed, and the fuzzer can be created once
+    /// it's done.
+    CfgNotReady,
+    /// The analysis of the CFG panicked.
+    AnalysisFailed,
+    CfgHashMismatch,
+    MissingFuzzerId,
+    MissingCrash,
+    SeedSha1Mismatch,
+    DuplicateBlockId(u64),
+    /// Block IDs must be dense, from 0 to the number of blocks.
+    BlockIdOutOfRange {
+        block_id: u64,
+        num_blocks: usize,
+    },
+    DanglingSuccessor {
+        block_id: u64,
+        successor: u64,
+    },
+    /// The sancov index can't be addressed by 32-bit features.
+    SancovIndexOutOfRange {
+        block_id: u64,
+        sancov_index: u64,
+    },
+    UnknownFuzzer(u64),
+    TooManyFuzzers(usize),
+    /// The fuzzer already has an open control stream.
+    DuplicateControlStream(u64),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::MissingCfg => write!(f, "Missing control flow graph."),
+            Error::UnknownCfg => write!(f, "Unknown control flow graph hash."),
+            Error::CfgNotReady => write!(f, "Control flow graph is still being analyzed."),
+            Error::AnalysisFailed => write!(f, "Failed to analyze the control flow graph."),
+            Error::CfgHashMismatch => write!(f, "Control flow graph doesn't match its hash."),
+            Error::MissingFuzzerId => write!(f, "Missing fuzzer ID."),
+            Error::MissingCrash => write!(f, "Missing crash."),
+            Error::SeedSha1Mismatch => write!(f, "Seed doesn't match its SHA1."),
+            Error::DuplicateBlockId(block_id) => write!(f, "Duplicate block ID {}.", block_id),
+            Error::BlockIdOutOfRange {
+                block_id,
+                num_blocks,
+            } => write!(
+                f,
+                "Block ID {} is out of range of {} blocks.",
+                block_id, num_blocks
+            ),
+            Error::DanglingSuccessor {
+                block_id,
+                successor,
+            } => write!(f, "Block {} has unknown successor {}.", block_id, successor),
+            Error::SancovIndexOutOfRange {
+                block_id,
+                sancov_index,
+            } => write!(
+                f,
+                "Block {} has out of range sancov index {}.",
+                block_id, sancov_index
+            ),
+            Error::UnknownFuzzer(fuzzer_id) => write!(f, "Unknown fuzzer ID {}.", fuzzer_id),
+            Error::TooManyFuzzers(limit) => write!(f, "Too many fuzzers, the limit is {}.", limit),
+            Error::DuplicateControlStream(fuzzer_id) => {
+                write!(f, "Fuzzer {} already has a control stream.", fuzzer_id)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for Status {
+    fn from(error: Error) -> Self {
+        let message = error.to_string();
+        match error {
+            Error::UnknownCfg | Error::UnknownFuzzer(_) => Status::not_found(message),
+            Error::TooManyFuzzers(_) => Status::resource_exhausted(message),
+            Error::CfgNotReady => Status::unavailable(message),
+            Error::AnalysisFailed => Status::internal(message),
+            Error::DuplicateControlStream(_) => Status::already_exists(message),
+            _ => Status::invalid_argument(message),
+        }
+    }
+}
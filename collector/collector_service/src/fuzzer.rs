@@ -12,146 +12,1173 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::{build_structure_graph, validate_cfg, BitCounter, Error};
 use common::{
-    collector_proto::ControlFlowGraph,
-    observer_proto::{structure_graph::Node as GraphNode, StructureGraph},
+    collector_proto::{ControlFlowGraph, FeatureLayout},
+    observer_proto::{structure_graph::Node as GraphNode, Feature, FeatureKind, StructureGraph},
     NO_SANCOV_INDEX,
 };
-use std::{cmp, collections::HashMap, iter};
+use std::{cmp, cmp::Ordering, collections::HashMap, mem, sync::Arc};
 
-#[derive(Clone)]
-struct Node {
-    bit_counter: u8,
+// Nodes and edges of the paths between two sancov indices.
+#[derive(Default)]
+struct SancovEdgePaths {
+    covered_nodes: Vec<usize>,
+    covered_edges: Vec<(usize, usize)>,
 }
 
+// IDs of the sancov indices, which the per-index arrays are indexed by, so
+// they're sized within a constant factor of the number of instrumented blocks
+// rather than by the largest index.
+enum SancovIds {
+    // Sancov indices below this are their own IDs, as few of them aren't
+    // instrumented. Saves a lookup for every feature.
+    Dense(usize),
+    // Sorted instrumented sancov indices, each at its ID.
+    Sparse(Vec<u32>),
+}
+
+// Sancov indices are their own IDs while there are at most this many times as
+// many of them as instrumented ones.
+const MAX_DENSE_SANCOV_ID_RATIO: usize = 4;
+
+/// Analysis of a fuzz target, shared by all fuzzers running it.
+pub struct Target {
+    struct_graph: StructureGraph,
+    sancov_ids: SancovIds,
+    // Pairs of sancov IDs in compressed sparse row layout. The pairs of sancov
+    // ID i are sancov_edge_starts[i]..sancov_edge_starts[i + 1], and the
+    // covered nodes and edges of pair j are found the same way.
+    sancov_edge_starts: Vec<usize>,
+    sancov_edge_dsts: Vec<usize>,
+    covered_node_starts: Vec<usize>,
+    covered_nodes: Vec<usize>,
+    covered_edge_starts: Vec<usize>,
+    covered_edges: Vec<usize>,
+    // CFG edges on the paths between sancov indices.
+    edges: Vec<(usize, usize)>,
+}
+
+/// Coverage state of a single fuzzer.
 pub struct Fuzzer {
-    nodes: Vec<Node>,
-    sancov_index_map: HashMap<u32, usize>,
-    sancov_edge_dict: HashMap<u32, Vec<(u32, Vec<usize>)>>,
+    target: Arc<Target>,
+    feature_layout: FeatureLayout,
+    bit_counters: Vec<BitCounter>,
+    edge_bit_counters: Vec<BitCounter>,
+    // Sorted features of the last update, which the next delta applies to.
+    // Only kept for streams which send deltas.
+    last_features: Vec<u32>,
+    // Buffer the next delta is applied into, swapped with last_features.
+    next_features: Vec<u32>,
+    // Scratch buffers of update_features, kept to avoid allocating on every
+    // update and cleared after each one.
+    sancov_bit_counters: Vec<BitCounter>,
+    covered_sancov_ids: Vec<usize>,
+    hit_nodes: Vec<bool>,
+    hit_node_indices: Vec<usize>,
+    hit_edges: Vec<bool>,
+    hit_edge_indices: Vec<usize>,
 }
 
-impl Fuzzer {
-    pub fn new(struct_graph: &StructureGraph, cfg: ControlFlowGraph) -> Self {
-        let node_sancov_map: HashMap<usize, u32> = cfg
-            .functions
-            .iter()
-            .map(|function| function.basic_blocks.iter())
-            .flatten()
-            .filter_map(|block| match block.sancov_index {
-                NO_SANCOV_INDEX => None,
-                sancov_index => Some((block.id as usize, sancov_index as u32)),
+/// Coverage hit by a feature update, with the bit counters accumulated so far.
+pub struct CoverageUpdate {
+    pub bit_counters: Vec<(usize, BitCounter)>,
+    pub edge_bit_counters: Vec<(usize, usize, BitCounter)>,
+}
+
+impl Target {
+    /// Validates the CFG and analyzes it.
+    pub fn from_cfg(cfg: &ControlFlowGraph) -> Result<Self, Error> {
+        validate_cfg(cfg)?;
+        Ok(Self::new(build_structure_graph(cfg), cfg))
+    }
+
+    fn new(struct_graph: StructureGraph, cfg: &ControlFlowGraph) -> Self {
+        let blocks = || {
+            cfg.functions
+                .iter()
+                .flat_map(|function| function.basic_blocks.iter())
+                .filter(|block| block.sancov_index != NO_SANCOV_INDEX)
+        };
+        let sancov_ids = SancovIds::new(blocks().map(|block| block.sancov_index as u32).collect());
+        let node_sancov_map: HashMap<usize, u32> = blocks()
+            .map(|block| {
+                let sancov_id = sancov_ids.get(block.sancov_index as u32).unwrap();
+                (block.id as usize, sancov_id as u32)
             })
             .collect();
-        Self {
-            nodes: iter::repeat(Node { bit_counter: 0 })
-                .take(struct_graph.nodes.len())
-                .collect(),
-            sancov_index_map: node_sancov_map
-                .iter()
-                .map(|(node_index, sancov_index)| (*sancov_index, *node_index))
-                .collect(),
-            sancov_edge_dict: Self::build_sancov_edge_dict(&struct_graph.nodes, &node_sancov_map),
+        let mut sancov_edge_dict =
+            Self::build_sancov_edge_dict(&struct_graph.nodes, &node_sancov_map);
+        let num_sancov_ids = sancov_ids.len();
+
+        let mut target = Self {
+            struct_graph,
+            sancov_ids,
+            sancov_edge_starts: vec![0],
+            sancov_edge_dsts: Vec::new(),
+            covered_node_starts: vec![0],
+            covered_nodes: Vec::new(),
+            covered_edge_starts: vec![0],
+            covered_edges: Vec::new(),
+            edges: Vec::new(),
+        };
+        let mut edge_ids: HashMap<(usize, usize), usize> = HashMap::new();
+        for sancov_id in 0..num_sancov_ids {
+            let mut sancov_edges = sancov_edge_dict
+                .remove(&(sancov_id as u32))
+                .unwrap_or_default();
+            sancov_edges.sort_unstable_by_key(|(dst, _)| *dst);
+            for (dst, edge_paths) in sancov_edges {
+                target.sancov_edge_dsts.push(dst as usize);
+                target.covered_nodes.extend(edge_paths.covered_nodes);
+                target.covered_node_starts.push(target.covered_nodes.len());
+                for edge in edge_paths.covered_edges {
+                    let edges = &mut target.edges;
+                    let edge_id = *edge_ids.entry(edge).or_insert_with(|| {
+                        edges.push(edge);
+                        edges.len() - 1
+                    });
+                    target.covered_edges.push(edge_id);
+                }
+                target.covered_edge_starts.push(target.covered_edges.len());
+            }
+            target
+                .sancov_edge_starts
+                .push(target.sancov_edge_dsts.len());
         }
+        target
     }
 
-    pub fn update_features(&mut self, features: &[u32]) -> Vec<(usize, u8)> {
-        let mut covered_sancov_indices: HashMap<u32, u8> = HashMap::new();
-        for feature in features {
-            let sancov_index = feature / 8;
-            if self.sancov_index_map.contains_key(&sancov_index) {
-                let bit_counter = covered_sancov_indices.entry(sancov_index).or_default();
-                *bit_counter |= 1 << (feature % 8);
+    pub fn struct_graph(&self) -> &StructureGraph {
+        &self.struct_graph
+    }
+
+    fn num_sancov_ids(&self) -> usize {
+        self.sancov_edge_starts.len() - 1
+    }
+
+    // Finds the nodes and edges on the paths between each pair of sancov
+    // indices, through uninstrumented nodes only. Paths from a sancov index
+    // which end in a dead end or a cycle are paired with the index itself.
+    //
+    // Paths are walks, which may go around loops of uninstrumented nodes as
+    // executions do, rather than simple paths. So on cyclic graphs, nodes only
+    // reachable through a loop, e.g. 0, 1 and 3 between the instrumented nodes
+    // 2 and 5 of [[1], [3], [4, 2], [2, 4], [5, 0], [], []], are covered too.
+    // Restricting to simple paths can't be done in near-linear time, as
+    // deciding whether a node is on a simple path between two others is
+    // NP-complete. On acyclic graphs, both are the same.
+    fn build_sancov_edge_dict(
+        graph_nodes: &[GraphNode],
+        node_sancov_map: &HashMap<usize, u32>,
+    ) -> HashMap<u32, Vec<(u32, SancovEdgePaths)>> {
+        let regions = UninstrumentedRegions::new(graph_nodes, node_sancov_map);
+        let mut sancov_edge_map: HashMap<(u32, u32), SancovEdgePaths> = HashMap::new();
+        let mut visited_by = vec![usize::MAX; graph_nodes.len()];
+        for (&source, &source_sancov_index) in node_sancov_map.iter() {
+            // Paths from the source, keyed by the node they end at.
+            let mut source_edge_paths: HashMap<usize, SancovEdgePaths> = HashMap::new();
+            if graph_nodes[source].successors.is_empty() {
+                source_edge_paths.entry(source).or_default();
+            }
+            let mut queue = vec![source];
+            let mut queue_index = 0;
+            while queue_index < queue.len() {
+                let node_index = queue[queue_index];
+                queue_index += 1;
+                if node_index != source {
+                    for end in regions.path_ends(node_index, source) {
+                        let edge_paths = source_edge_paths.entry(end).or_default();
+                        edge_paths.covered_nodes.push(node_index);
+                    }
+                }
+                for successor in graph_nodes[node_index].successors.iter() {
+                    let successor = *successor as usize;
+                    if node_sancov_map.contains_key(&successor) {
+                        let edge_paths = source_edge_paths.entry(successor).or_default();
+                        edge_paths.covered_edges.push((node_index, successor));
+                        continue;
+                    }
+                    for end in regions.path_ends(successor, source) {
+                        let edge_paths = source_edge_paths.entry(end).or_default();
+                        edge_paths.covered_edges.push((node_index, successor));
+                    }
+                    if visited_by[successor] != source {
+                        visited_by[successor] = source;
+                        queue.push(successor);
+                    }
+                }
+            }
+
+            for (end, edge_paths) in source_edge_paths {
+                let sancov_index = node_sancov_map[&end];
+                let merged_edge_paths = sancov_edge_map
+                    .entry((
+                        cmp::min(source_sancov_index, sancov_index),
+                        cmp::max(source_sancov_index, sancov_index),
+                    ))
+                    .or_default();
+                merged_edge_paths.covered_nodes.push(source);
+                merged_edge_paths.covered_nodes.push(end);
+                merged_edge_paths
+                    .covered_nodes
+                    .extend(edge_paths.covered_nodes);
+                merged_edge_paths
+                    .covered_edges
+                    .extend(edge_paths.covered_edges);
             }
         }
-        let mut hit_bit_counters: HashMap<usize, u8> = HashMap::new();
-        for (sancov_index, bit_counter) in covered_sancov_indices.iter() {
-            if let Some(edges) = self.sancov_edge_dict.get(&sancov_index) {
-                for (dst, covered_nodes) in edges {
-                    if !covered_sancov_indices.contains_key(dst) {
+
+        let mut sancov_edge_dict: HashMap<u32, Vec<(u32, SancovEdgePaths)>> = HashMap::new();
+        for ((src, dst), mut edge_paths) in sancov_edge_map {
+            edge_paths.covered_nodes.sort_unstable();
+            edge_paths.covered_nodes.dedup();
+            edge_paths.covered_edges.sort_unstable();
+            edge_paths.covered_edges.dedup();
+            sancov_edge_dict
+                .entry(src)
+                .or_default()
+                .push((dst, edge_paths));
+        }
+        sancov_edge_dict
+    }
+}
+
+impl SancovIds {
+    fn new(mut sancov_indices: Vec<u32>) -> Self {
+        sancov_indices.sort_unstable();
+        sancov_indices.dedup();
+        let num_sancov_indices = sancov_indices
+            .last()
+            .map_or(0, |sancov_index| *sancov_index as usize + 1);
+        if num_sancov_indices > sancov_indices.len() * MAX_DENSE_SANCOV_ID_RATIO {
+            return Self::Sparse(sancov_indices);
+        }
+        Self::Dense(num_sancov_indices)
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Self::Dense(num_sancov_indices) => *num_sancov_indices,
+            Self::Sparse(sancov_indices) => sancov_indices.len(),
+        }
+    }
+
+    fn get(&self, sancov_index: u32) -> Option<usize> {
+        match self {
+            Self::Dense(num_sancov_indices) => {
+                Some(sancov_index as usize).filter(|sancov_id| sancov_id < num_sancov_indices)
+            }
+            Self::Sparse(sancov_indices) => sancov_indices.binary_search(&sancov_index).ok(),
+        }
+    }
+}
+
+// Strongly connected components of the uninstrumented nodes, each with the
+// ends of the paths leaving it, so the paths don't have to be walked again for
+// every sancov index which leads into them.
+struct UninstrumentedRegions {
+    // Component of each uninstrumented node.
+    components: Vec<usize>,
+    // Instrumented nodes reachable from each component through uninstrumented
+    // nodes, sorted.
+    exits: Vec<Vec<usize>>,
+    // Whether a dead end or a cycle is reachable from each component.
+    reaches_sink: Vec<bool>,
+}
+
+impl UninstrumentedRegions {
+    // Runs Tarjan's algorithm with an explicit stack, since chains of
+    // uninstrumented nodes can be too long for recursion. Components are
+    // completed after all the components reachable from them, so their exits
+    // are merged from the already completed successors.
+    fn new(graph_nodes: &[GraphNode], node_sancov_map: &HashMap<usize, u32>) -> Self {
+        const UNVISITED: usize = usize::MAX;
+        let is_uninstrumented = |node_index: usize| !node_sancov_map.contains_key(&node_index);
+        let mut regions = Self {
+            components: vec![UNVISITED; graph_nodes.len()],
+            exits: Vec::new(),
+            reaches_sink: Vec::new(),
+        };
+        let mut indices = vec![UNVISITED; graph_nodes.len()];
+        let mut low_links = vec![0; graph_nodes.len()];
+        let mut on_stack = vec![false; graph_nodes.len()];
+        let mut component_stack = Vec::new();
+        // Visited nodes with the position of the next successor to visit.
+        let mut call_stack: Vec<(usize, usize)> = Vec::new();
+        let mut next_index = 0;
+        for root in 0..graph_nodes.len() {
+            if !is_uninstrumented(root) || indices[root] != UNVISITED {
+                continue;
+            }
+            indices[root] = next_index;
+            low_links[root] = next_index;
+            next_index += 1;
+            component_stack.push(root);
+            on_stack[root] = true;
+            call_stack.push((root, 0));
+            while let Some(&(node_index, successor_position)) = call_stack.last() {
+                let successors = &graph_nodes[node_index].successors;
+                if successor_position < successors.len() {
+                    call_stack.last_mut().unwrap().1 += 1;
+                    let successor = successors[successor_position] as usize;
+                    if !is_uninstrumented(successor) {
                         continue;
                     }
-                    for node_index in covered_nodes {
-                        let updated_bit_counter = self.nodes[*node_index].bit_counter | bit_counter;
-                        self.nodes[*node_index].bit_counter = updated_bit_counter;
-                        hit_bit_counters.insert(*node_index, updated_bit_counter);
+                    if indices[successor] == UNVISITED {
+                        indices[successor] = next_index;
+                        low_links[successor] = next_index;
+                        next_index += 1;
+                        component_stack.push(successor);
+                        on_stack[successor] = true;
+                        call_stack.push((successor, 0));
+                    } else if on_stack[successor] {
+                        low_links[node_index] = cmp::min(low_links[node_index], indices[successor]);
                     }
+                    continue;
+                }
+
+                call_stack.pop();
+                if let Some(&(parent, _)) = call_stack.last() {
+                    low_links[parent] = cmp::min(low_links[parent], low_links[node_index]);
+                }
+                if low_links[node_index] == indices[node_index] {
+                    let mut members = Vec::new();
+                    loop {
+                        let member = component_stack.pop().unwrap();
+                        on_stack[member] = false;
+                        members.push(member);
+                        if member == node_index {
+                            break;
+                        }
+                    }
+                    regions.complete_component(graph_nodes, node_sancov_map, &members);
                 }
             }
         }
-        hit_bit_counters.into_iter().collect()
+        regions
     }
 
-    fn build_sancov_edge_dict(
+    fn complete_component(
+        &mut self,
         graph_nodes: &[GraphNode],
         node_sancov_map: &HashMap<usize, u32>,
-    ) -> HashMap<u32, Vec<(u32, Vec<usize>)>> {
-        let mut sancov_edge_map: HashMap<(u32, u32), Vec<usize>> = HashMap::new();
-        let mut visiting_map = vec![NO_SANCOV_INDEX; graph_nodes.len()];
-        for node_index in 0..graph_nodes.len() {
-            let source_sancov_index = match node_sancov_map.get(&node_index) {
-                Some(sancov_index) => sancov_index,
-                None => continue,
-            };
-            let mut path = Vec::new();
-            Self::path_traverse(
-                graph_nodes,
+        members: &[usize],
+    ) {
+        let component = self.exits.len();
+        for member in members {
+            self.components[*member] = component;
+        }
+        let mut exits = Vec::new();
+        let mut reaches_sink = false;
+        for member in members {
+            let successors = &graph_nodes[*member].successors;
+            reaches_sink |= successors.is_empty();
+            for successor in successors.iter() {
+                let successor = *successor as usize;
+                if node_sancov_map.contains_key(&successor) {
+                    exits.push(successor);
+                } else if self.components[successor] == component {
+                    // Any edge inside the component closes a cycle.
+                    reaches_sink = true;
+                } else {
+                    let successor_component = self.components[successor];
+                    exits.extend_from_slice(&self.exits[successor_component]);
+                    reaches_sink |= self.reaches_sink[successor_component];
+                }
+            }
+        }
+        exits.sort_unstable();
+        exits.dedup();
+        self.exits.push(exits);
+        self.reaches_sink.push(reaches_sink);
+    }
+
+    // Ends of the paths from the source through the uninstrumented node.
+    fn path_ends(&self, node_index: usize, source: usize) -> impl Iterator<Item = usize> + '_ {
+        let component = self.components[node_index];
+        let sink_end = if self.reaches_sink[component] {
+            Some(source)
+        } else {
+            None
+        };
+        self.exits[component].iter().copied().chain(sink_end)
+    }
+}
+
+impl Fuzzer {
+    pub fn new(target: Arc<Target>, feature_layout: Option<FeatureLayout>) -> Self {
+        let num_nodes = target.struct_graph.nodes.len();
+        let num_edges = target.edges.len();
+        Self {
+            bit_counters: vec![BitCounter::default(); num_nodes],
+            edge_bit_counters: vec![BitCounter::default(); num_edges],
+            last_features: Vec::new(),
+            next_features: Vec::new(),
+            sancov_bit_counters: vec![BitCounter::default(); target.num_sancov_ids()],
+            covered_sancov_ids: Vec::new(),
+            hit_nodes: vec![false; num_nodes],
+            hit_node_indices: Vec::new(),
+            hit_edges: vec![false; num_edges],
+            hit_edge_indices: Vec::new(),
+            target,
+            feature_layout: feature_layout.unwrap_or(FeatureLayout {
+                counters_size: u64::MAX,
+                value_profile_size: 0,
+            }),
+        }
+    }
+
+    pub fn target(&self) -> &Arc<Target> {
+        &self.target
+    }
+
+    /// Decodes the features of an update, sent either in full or, if `delta`
+    /// is set, as the changes since the previous update of the fuzzer. The
+    /// features are only kept for the next delta if the update is one, or
+    /// `delta_base` is set.
+    pub fn decode_update(
+        &mut self,
+        mut features: Vec<u32>,
+        mut removed_features: Vec<u32>,
+        delta: bool,
+        delta_base: bool,
+    ) -> Vec<Feature> {
+        if delta {
+            features.sort_unstable();
+            removed_features.sort_unstable();
+            apply_delta(
+                &self.last_features,
+                &features,
+                &removed_features,
+                &mut self.next_features,
+            );
+            mem::swap(&mut self.last_features, &mut self.next_features);
+        } else if delta_base {
+            // Already sorted by the encoder, which makes this cheap.
+            features.sort_unstable();
+            features.dedup();
+            self.last_features = features;
+        } else {
+            self.last_features.clear();
+            return self.decode_features(&features);
+        }
+        self.decode_features(&self.last_features)
+    }
+
+    pub fn decode_features(&self, features: &[u32]) -> Vec<Feature> {
+        let layout = &self.feature_layout;
+        features
+            .iter()
+            .map(|&feature| {
+                let mut index = feature as u64;
+                let kind = if index < layout.counters_size {
+                    FeatureKind::Counter
+                } else {
+                    index -= layout.counters_size;
+                    if index < layout.value_profile_size {
+                        FeatureKind::ValueProfile
+                    } else {
+                        index -= layout.value_profile_size;
+                        FeatureKind::StackDepth
+                    }
+                };
+                Feature {
+                    kind: kind as i32,
+                    index: index as u32,
+                }
+            })
+            .collect()
+    }
+
+    pub fn update_features(&mut self, features: &[Feature]) -> CoverageUpdate {
+        for feature in features {
+            if feature.kind() != FeatureKind::Counter {
+                continue;
+            }
+            if let Some(sancov_id) = self.target.sancov_ids.get(feature.index / 8) {
+                let bit_counter = &mut self.sancov_bit_counters[sancov_id];
+                if bit_counter.is_empty() {
+                    self.covered_sancov_ids.push(sancov_id);
+                }
+                *bit_counter |= BitCounter::new(1 << (feature.index % 8));
+            }
+        }
+
+        let target = &*self.target;
+        for sancov_id in self.covered_sancov_ids.iter() {
+            let bit_counter = self.sancov_bit_counters[*sancov_id];
+            for sancov_edge in
+                target.sancov_edge_starts[*sancov_id]..target.sancov_edge_starts[*sancov_id + 1]
+            {
+                if self.sancov_bit_counters[target.sancov_edge_dsts[sancov_edge]].is_empty() {
+                    continue;
+                }
+                let covered_nodes = &target.covered_nodes[target.covered_node_starts[sancov_edge]
+                    ..target.covered_node_starts[sancov_edge + 1]];
+                for node_index in covered_nodes {
+                    self.bit_counters[*node_index] |= bit_counter;
+                    if !self.hit_nodes[*node_index] {
+                        self.hit_nodes[*node_index] = true;
+                        self.hit_node_indices.push(*node_index);
+                    }
+                }
+                let covered_edges = &target.covered_edges[target.covered_edge_starts[sancov_edge]
+                    ..target.covered_edge_starts[sancov_edge + 1]];
+                for edge_id in covered_edges {
+                    self.edge_bit_counters[*edge_id] |= bit_counter;
+                    if !self.hit_edges[*edge_id] {
+                        self.hit_edges[*edge_id] = true;
+                        self.hit_edge_indices.push(*edge_id);
+                    }
+                }
+            }
+        }
+
+        for sancov_id in self.covered_sancov_ids.drain(..) {
+            self.sancov_bit_counters[sancov_id] = BitCounter::default();
+        }
+        let hit_nodes = &mut self.hit_nodes;
+        let bit_counters = &self.bit_counters;
+        let hit_edges = &mut self.hit_edges;
+        let edge_bit_counters = &self.edge_bit_counters;
+        CoverageUpdate {
+            bit_counters: self
+                .hit_node_indices
+                .drain(..)
+                .map(|node_index| {
+                    hit_nodes[node_index] = false;
+                    (node_index, bit_counters[node_index])
+                })
+                .collect(),
+            edge_bit_counters: self
+                .hit_edge_indices
+                .drain(..)
+                .map(|edge_id| {
+                    hit_edges[edge_id] = false;
+                    let (src, dst) = target.edges[edge_id];
+                    (src, dst, edge_bit_counters[edge_id])
+                })
+                .collect(),
+        }
+    }
+}
+
+// Merges the sorted base features with the added ones, without the removed
+// ones, into the output.
+fn apply_delta(base: &[u32], added: &[u32], removed: &[u32], output: &mut Vec<u32>) {
+    output.clear();
+    let mut removed = removed.iter().peekable();
+    let (mut base_index, mut added_index) = (0, 0);
+    while base_index < base.len() || added_index < added.len() {
+        let feature = match (base.get(base_index), added.get(added_index)) {
+            (Some(base_feature), Some(added_feature)) => match base_feature.cmp(added_feature) {
+                Ordering::Less => {
+                    base_index += 1;
+                    *base_feature
+                }
+                Ordering::Greater => {
+                    added_index += 1;
+                    *added_feature
+                }
+                Ordering::Equal => {
+                    base_index += 1;
+                    added_index += 1;
+                    *base_feature
+                }
+            },
+            (Some(base_feature), None) => {
+                base_index += 1;
+                *base_feature
+            }
+            (None, Some(added_feature)) => {
+                added_index += 1;
+                *added_feature
+            }
+            (None, None) => unreachable!(),
+        };
+        while removed
+            .next_if(|removed_feature| **removed_feature < feature)
+            .is_some()
+        {}
+        if removed.peek() == Some(&&feature) || output.last() == Some(&feature) {
+            continue;
+        }
+        output.push(feature);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{
+        collector_proto::{
+            control_flow_graph::{BasicBlock, Function},
+            UpdateFeaturesRequest,
+        },
+        FeatureEncoder,
+    };
+    use std::collections::{BTreeMap, BTreeSet};
+
+    type SancovPairs = BTreeMap<(u32, u32), (BTreeSet<usize>, BTreeSet<(usize, usize)>)>;
+
+    // Xorshift64, so the randomized tests see the same cases on every run.
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, bound: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % bound
+        }
+    }
+
+    fn build_graph_nodes(successors: &[Vec<u64>]) -> Vec<GraphNode> {
+        successors
+            .iter()
+            .map(|successors| GraphNode {
+                predecessors: Vec::new(),
+                successors: successors.clone(),
+            })
+            .collect()
+    }
+
+    fn sancov_pairs(successors: &[Vec<u64>], node_sancov_map: &HashMap<usize, u32>) -> SancovPairs {
+        let graph_nodes = build_graph_nodes(successors);
+        let mut pairs = SancovPairs::new();
+        for (src, sancov_edges) in Target::build_sancov_edge_dict(&graph_nodes, node_sancov_map) {
+            for (dst, edge_paths) in sancov_edges {
+                let pair = pairs.entry((src, dst)).or_default();
+                pair.0.extend(edge_paths.covered_nodes);
+                pair.1.extend(edge_paths.covered_edges);
+            }
+        }
+        pairs
+    }
+
+    // Enumerates the simple paths from every sancov index, which takes
+    // exponential time. Only agrees with walks on acyclic graphs.
+    fn simple_path_pairs(
+        successors: &[Vec<u64>],
+        node_sancov_map: &HashMap<usize, u32>,
+    ) -> SancovPairs {
+        fn traverse(
+            successors: &[Vec<u64>],
+            node_sancov_map: &HashMap<usize, u32>,
+            node_index: usize,
+            source_sancov_index: u32,
+            path: &mut Vec<usize>,
+            pairs: &mut SancovPairs,
+        ) {
+            let sancov_index = node_sancov_map.get(&node_index).copied();
+            let revisited = path.contains(&node_index);
+            path.push(node_index);
+            if (path.len() > 1 && sancov_index.is_some())
+                || successors[node_index].is_empty()
+                || revisited
+            {
+                let sancov_index = sancov_index.unwrap_or(source_sancov_index);
+                let pair = pairs
+                    .entry((
+                        cmp::min(source_sancov_index, sancov_index),
+                        cmp::max(source_sancov_index, sancov_index),
+                    ))
+                    .or_default();
+                pair.0.extend(path.iter().copied());
+                pair.1
+                    .extend(path.windows(2).map(|edge| (edge[0], edge[1])));
+            } else {
+                for successor in successors[node_index].iter() {
+                    traverse(
+                        successors,
+                        node_sancov_map,
+                        *successor as usize,
+                        source_sancov_index,
+                        path,
+                        pairs,
+                    );
+                }
+            }
+            path.pop();
+        }
+
+        let mut pairs = SancovPairs::new();
+        for (&node_index, &sancov_index) in node_sancov_map.iter() {
+            traverse(
+                successors,
                 node_sancov_map,
                 node_index,
-                *source_sancov_index,
-                &mut path,
-                &mut sancov_edge_map,
-                &mut visiting_map,
+                sancov_index,
+                &mut Vec::new(),
+                &mut pairs,
             );
         }
-        let mut sancov_edge_dict: HashMap<u32, Vec<(u32, Vec<usize>)>> = HashMap::new();
-        for ((src, dst), covered_nodes) in sancov_edge_map {
-            sancov_edge_dict
-                .entry(src)
-                .or_default()
-                .push((dst, covered_nodes));
+        pairs
+    }
+
+    // Finds the walks through uninstrumented nodes by brute force reachability.
+    fn walk_pairs(successors: &[Vec<u64>], node_sancov_map: &HashMap<usize, u32>) -> SancovPairs {
+        let is_uninstrumented = |node_index: usize| !node_sancov_map.contains_key(&node_index);
+        // Uninstrumented nodes reachable from the node through uninstrumented
+        // nodes, in at least one step.
+        let reachable = |node_index: usize| {
+            let mut visited = BTreeSet::new();
+            let mut stack = vec![node_index];
+            while let Some(node_index) = stack.pop() {
+                for successor in successors[node_index].iter() {
+                    let successor = *successor as usize;
+                    if is_uninstrumented(successor) && visited.insert(successor) {
+                        stack.push(successor);
+                    }
+                }
+            }
+            visited
+        };
+        // Ends of the walks from the uninstrumented node, with None for sinks.
+        let ends = |node_index: usize| {
+            let mut ends = BTreeSet::new();
+            let mut region = reachable(node_index);
+            region.insert(node_index);
+            for &member in region.iter() {
+                if successors[member].is_empty() || reachable(member).contains(&member) {
+                    ends.insert(None);
+                }
+                for successor in successors[member].iter() {
+                    let successor = *successor as usize;
+                    if !is_uninstrumented(successor) {
+                        ends.insert(Some(successor));
+                    }
+                }
+            }
+            ends
+        };
+
+        let mut pairs = SancovPairs::new();
+        for (&source, &source_sancov_index) in node_sancov_map.iter() {
+            let mut add = |end: usize, nodes: &[usize], edge: Option<(usize, usize)>| {
+                let sancov_index = node_sancov_map[&end];
+                let pair = pairs
+                    .entry((
+                        cmp::min(source_sancov_index, sancov_index),
+                        cmp::max(source_sancov_index, sancov_index),
+                    ))
+                    .or_default();
+                pair.0.extend([source, end].iter().chain(nodes));
+                pair.1.extend(edge);
+            };
+            if successors[source].is_empty() {
+                add(source, &[], None);
+            }
+            let mut region = reachable(source);
+            region.insert(source);
+            for &node_index in region.iter() {
+                if node_index != source {
+                    for end in ends(node_index) {
+                        add(end.unwrap_or(source), &[node_index], None);
+                    }
+                }
+                for successor in successors[node_index].iter() {
+                    let successor = *successor as usize;
+                    let edge = Some((node_index, successor));
+                    if !is_uninstrumented(successor) {
+                        add(successor, &[], edge);
+                        continue;
+                    }
+                    for end in ends(successor) {
+                        add(end.unwrap_or(source), &[], edge);
+                    }
+                }
+            }
         }
-        sancov_edge_dict
+        pairs
     }
 
-    fn path_traverse(
-        graph_nodes: &[GraphNode],
-        node_sancov_map: &HashMap<usize, u32>,
-        node_index: usize,
-        source_sancov_index: u32,
-        path: &mut Vec<usize>,
-        sancov_edge_map: &mut HashMap<(u32, u32), Vec<usize>>,
-        visiting_map: &mut Vec<u64>,
-    ) {
-        path.push(node_index);
-        let sancov_index = node_sancov_map.get(&node_index).copied();
-        if (path.len() > 1 && sancov_index.is_some())
-            || graph_nodes[node_index].successors.is_empty()
-            || visiting_map[node_index] == source_sancov_index as u64
+    // Generates graphs of up to 10 nodes, with edges only to later nodes if
+    // acyclic.
+    fn random_graphs(
+        seed: u64,
+        acyclic: bool,
+    ) -> impl Iterator<Item = (Vec<Vec<u64>>, HashMap<usize, u32>)> {
+        let mut rng = Rng(seed);
+        (0..2000).map(move |_| {
+            let num_nodes = rng.below(10) + 1;
+            let mut node_sancov_map = HashMap::new();
+            let successors = (0..num_nodes)
+                .map(|node_index| {
+                    if rng.below(2) == 0 {
+                        let sancov_index = node_sancov_map.len() as u32;
+                        node_sancov_map.insert(node_index as usize, sancov_index);
+                    }
+                    let first_successor = if acyclic { node_index + 1 } else { 0 };
+                    if first_successor == num_nodes {
+                        return Vec::new();
+                    }
+                    let mut successors: Vec<u64> = (0..rng.below(4))
+                        .map(|_| first_successor + rng.below(num_nodes - first_successor))
+                        .collect();
+                    successors.sort_unstable();
+                    successors.dedup();
+                    successors
+                })
+                .collect();
+            (successors, node_sancov_map)
+        })
+    }
+
+    #[test]
+    fn sancov_ids_are_bounded_by_instrumented_indices() {
+        let sancov_ids = SancovIds::new(vec![3, 0, 2, 5, 2]);
+        assert!(matches!(sancov_ids, SancovIds::Dense(6)));
+        assert_eq!(sancov_ids.get(5), Some(5));
+        assert_eq!(sancov_ids.get(6), None);
+
+        let sancov_ids = SancovIds::new(vec![7, 1 << 20, 0, u32::MAX / 8]);
+        assert!(matches!(sancov_ids, SancovIds::Sparse(_)));
+        assert_eq!(sancov_ids.len(), 4);
+        assert_eq!(sancov_ids.get(0), Some(0));
+        assert_eq!(sancov_ids.get(7), Some(1));
+        assert_eq!(sancov_ids.get(1 << 20), Some(2));
+        assert_eq!(sancov_ids.get(u32::MAX / 8), Some(3));
+        for sancov_index in [1, 8, (1 << 20) + 1, u32::MAX].iter() {
+            assert_eq!(sancov_ids.get(*sancov_index), None);
+        }
+        assert_eq!(SancovIds::new(Vec::new()).len(), 0);
+    }
+
+    #[test]
+    fn acyclic_paths_match_simple_paths() {
+        for (successors, node_sancov_map) in random_graphs(0x9e37_79b9_7f4a_7c15, true) {
+            assert_eq!(
+                sancov_pairs(&successors, &node_sancov_map),
+                simple_path_pairs(&successors, &node_sancov_map),
+                "{:?} {:?}",
+                successors,
+                node_sancov_map
+            );
+        }
+    }
+
+    #[test]
+    fn cyclic_paths_match_walks() {
+        for (successors, node_sancov_map) in random_graphs(0x2545_f491_4f6c_dd1d, false) {
+            assert_eq!(
+                sancov_pairs(&successors, &node_sancov_map),
+                walk_pairs(&successors, &node_sancov_map),
+                "{:?} {:?}",
+                successors,
+                node_sancov_map
+            );
+        }
+    }
+
+    #[test]
+    fn uninstrumented_loop_is_covered() {
+        let successors = vec![
+            vec![1],
+            vec![3],
+            vec![4, 2],
+            vec![2, 4],
+            vec![5, 0],
+            vec![],
+            vec![],
+        ];
+        let node_sancov_map: HashMap<usize, u32> =
+            [(6, 2), (2, 0), (5, 1)].iter().copied().collect();
+        let pairs = sancov_pairs(&successors, &node_sancov_map);
+
+        // Simple paths only reach 5 through 4, but walks can go around
+        // 4 -> 0 -> 1 -> 3 -> 4 first.
+        let (nodes, edges) = &pairs[&(0, 1)];
+        assert_eq!(nodes, &(0..=5).collect());
+        assert_eq!(
+            edges,
+            &[(0, 1), (1, 3), (2, 4), (3, 4), (4, 0), (4, 5)]
+                .iter()
+                .copied()
+                .collect()
+        );
+        let (nodes, edges) = &pairs[&(0, 0)];
+        assert_eq!(nodes, &[0, 1, 2, 3, 4].iter().copied().collect());
+        assert_eq!(
+            edges,
+            &[(0, 1), (1, 3), (2, 2), (2, 4), (3, 2), (3, 4), (4, 0)]
+                .iter()
+                .copied()
+                .collect()
+        );
+        // Nothing leaves 5, and 6 isn't reachable.
+        let (nodes, edges) = &pairs[&(1, 1)];
+        assert_eq!(nodes, &[5].iter().copied().collect());
+        assert!(edges.is_empty());
+        assert_eq!(pairs[&(2, 2)].0, [6].iter().copied().collect());
+        assert_eq!(pairs.len(), 4);
+    }
+
+    #[test]
+    fn uninstrumented_self_loop_pairs_source_with_itself() {
+        // 0 -> 1 -> 1 -> 2, with 0 and 2 instrumented.
+        let successors = vec![vec![1], vec![1, 2], vec![]];
+        let node_sancov_map: HashMap<usize, u32> = [(0, 0), (2, 1)].iter().copied().collect();
+        let pairs = sancov_pairs(&successors, &node_sancov_map);
+
+        let (nodes, edges) = &pairs[&(0, 1)];
+        assert_eq!(nodes, &[0, 1, 2].iter().copied().collect());
+        assert_eq!(edges, &[(0, 1), (1, 1), (1, 2)].iter().copied().collect());
+        let (nodes, edges) = &pairs[&(0, 0)];
+        assert_eq!(nodes, &[0, 1].iter().copied().collect());
+        assert_eq!(edges, &[(0, 1), (1, 1)].iter().copied().collect());
+    }
+
+    fn build_fuzzer() -> Fuzzer {
+        let target = Target::from_cfg(&ControlFlowGraph::default()).unwrap();
+        Fuzzer::new(Arc::new(target), None)
+    }
+
+    fn decode(fuzzer: &mut Fuzzer, req: UpdateFeaturesRequest) -> Vec<Feature> {
+        fuzzer.decode_update(
+            req.features,
+            req.removed_features,
+            req.delta,
+            req.delta_base,
+        )
+    }
+
+    // Feature sets of consecutive executions, which mostly share their
+    // features.
+    fn feature_sets() -> impl Iterator<Item = Vec<u32>> {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        let mut features: Vec<u32> = (0..64).map(|_| rng.below(256) as u32).collect();
+        (0..1000).map(move |_| {
+            for _ in 0..rng.below(8) {
+                let index = rng.below(features.len() as u64) as usize;
+                features[index] = rng.below(256) as u32;
+            }
+            features.clone()
+        })
+    }
+
+    fn expected_features(fuzzer: &Fuzzer, features: &[u32]) -> Vec<Feature> {
+        let mut features = features.to_vec();
+        features.sort_unstable();
+        features.dedup();
+        fuzzer.decode_features(&features)
+    }
+
+    #[test]
+    fn apply_delta_merges_sorted_features() {
+        let mut output = vec![42];
+        apply_delta(&[1, 3, 5, 7], &[2, 2, 6, 9], &[3, 7, 8], &mut output);
+        assert_eq!(output, [1, 2, 5, 6, 9]);
+        apply_delta(&[], &[4], &[], &mut output);
+        assert_eq!(output, [4]);
+        apply_delta(&[4], &[], &[4], &mut output);
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn decodes_encoded_updates() {
+        let mut encoder = FeatureEncoder::default();
+        let mut fuzzer = build_fuzzer();
+        let mut num_deltas = 0;
+        for features in feature_sets() {
+            let mut req = UpdateFeaturesRequest {
+                features: features.clone(),
+                ..Default::default()
+            };
+            encoder.encode(&mut req);
+            num_deltas += req.delta as usize;
+            assert_eq!(
+                decode(&mut fuzzer, req),
+                expected_features(&fuzzer, &features)
+            );
+        }
+        assert!(num_deltas > 0);
+    }
+
+    #[test]
+    fn full_snapshot_reconciles_lost_delta() {
+        let mut encoder = FeatureEncoder::default();
+        let mut fuzzer = build_fuzzer();
+        let (mut out_of_sync, mut reconciled) = (false, false);
+        for (index, features) in feature_sets().enumerate() {
+            let mut req = UpdateFeaturesRequest {
+                features: features.clone(),
+                ..Default::default()
+            };
+            encoder.encode(&mut req);
+            if index == 10 {
+                assert!(req.delta);
+                continue;
+            }
+            reconciled |= index > 10 && !req.delta;
+            let decoded_features = decode(&mut fuzzer, req);
+            let expected_features = expected_features(&fuzzer, &features);
+            if index < 10 || reconciled {
+                assert_eq!(decoded_features, expected_features);
+            } else {
+                out_of_sync |= decoded_features != expected_features;
+            }
+        }
+        assert!(out_of_sync && reconciled);
+    }
+
+    #[test]
+    fn full_updates_keep_no_state() {
+        let mut fuzzer = build_fuzzer();
+        let features = decode(
+            &mut fuzzer,
+            UpdateFeaturesRequest {
+                features: vec![3, 1, 2],
+                ..Default::default()
+            },
+        );
+        assert_eq!(features, fuzzer.decode_features(&[3, 1, 2]));
+        assert!(fuzzer.last_features.is_empty());
+        decode(
+            &mut fuzzer,
+            UpdateFeaturesRequest {
+                features: vec![3, 1, 2],
+                delta_base: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(fuzzer.last_features, [1, 2, 3]);
+    }
+
+    // The hash map based attribution of the coverage which update_features
+    // replaced, as a reference.
+    struct ReferenceFuzzer {
+        sancov_edge_dict: HashMap<u32, Vec<(u32, SancovEdgePaths)>>,
+        bit_counters: HashMap<usize, BitCounter>,
+        edge_bit_counters: HashMap<(usize, usize), BitCounter>,
+    }
+
+    impl ReferenceFuzzer {
+        fn update_features(&mut self, features: &[Feature]) -> CoverageUpdate {
+            let mut covered_sancov_indices: HashMap<u32, BitCounter> = HashMap::new();
+            for feature in features {
+                if feature.kind() != FeatureKind::Counter {
+                    continue;
+                }
+                let bit_counter = covered_sancov_indices.entry(feature.index / 8).or_default();
+                *bit_counter |= BitCounter::new(1 << (feature.index % 8));
+            }
+            let mut hit_bit_counters = HashMap::new();
+            let mut hit_edge_bit_counters = HashMap::new();
+            for (sancov_index, bit_counter) in covered_sancov_indices.iter() {
+                for (dst, edge_paths) in self
+                    .sancov_edge_dict
+                    .get(sancov_index)
+                    .into_iter()
+                    .flatten()
+                {
+                    if !covered_sancov_indices.contains_key(dst) {
+                        continue;
+                    }
+                    for node_index in edge_paths.covered_nodes.iter() {
+                        let node_bit_counter = self.bit_counters.entry(*node_index).or_default();
+                        *node_bit_counter |= *bit_counter;
+                        hit_bit_counters.insert(*node_index, *node_bit_counter);
+                    }
+                    for edge in edge_paths.covered_edges.iter() {
+                        let edge_bit_counter = self.edge_bit_counters.entry(*edge).or_default();
+                        *edge_bit_counter |= *bit_counter;
+                        hit_edge_bit_counters.insert(*edge, *edge_bit_counter);
+                    }
+                }
+            }
+            CoverageUpdate {
+                bit_counters: hit_bit_counters.into_iter().collect(),
+                edge_bit_counters: hit_edge_bit_counters
+                    .into_iter()
+                    .map(|((src, dst), bit_counter)| (src, dst, bit_counter))
+                    .collect(),
+            }
+        }
+    }
+
+    fn sort_coverage_update(mut coverage_update: CoverageUpdate) -> CoverageUpdate {
+        coverage_update
+            .bit_counters
+            .sort_unstable_by_key(|(node_index, _)| *node_index);
+        coverage_update
+            .edge_bit_counters
+            .sort_unstable_by_key(|(src, dst, _)| (*src, *dst));
+        coverage_update
+    }
+
+    #[test]
+    fn update_features_matches_reference() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        let mut num_hit_nodes = 0;
+        for (graph_index, (successors, node_sancov_map)) in
+            random_graphs(0x9e37_79b9_7f4a_7c15, false).enumerate()
         {
-            let sancov_index = sancov_index.unwrap_or(source_sancov_index);
-            let edge_path = sancov_edge_map
-                .entry((
-                    cmp::min(source_sancov_index, sancov_index),
-                    cmp::max(source_sancov_index, sancov_index),
-                ))
-                .or_default();
-            edge_path.extend_from_slice(&path);
-            edge_path.dedup();
-        } else {
-            visiting_map[node_index] = source_sancov_index as u64;
-            for successor in graph_nodes[node_index].successors.iter() {
-                Self::path_traverse(
-                    graph_nodes,
-                    node_sancov_map,
-                    *successor as usize,
-                    source_sancov_index,
-                    path,
-                    sancov_edge_map,
-                    visiting_map,
+            // Spreads the sancov indices to cover both ID lookups, up to the
+            // largest one features can address.
+            let max_sancov_index = (u32::MAX / 8) as u64;
+            let node_sancov_map: HashMap<usize, u32> = node_sancov_map
+                .into_iter()
+                .map(|(node_index, sancov_index)| {
+                    let sancov_index = match graph_index % 3 {
+                        0 => sancov_index as u64,
+                        1 => sancov_index as u64 * 9973 + 5,
+                        _ => max_sancov_index - sancov_index as u64,
+                    };
+                    (node_index, sancov_index as u32)
+                })
+                .collect();
+            let cfg = ControlFlowGraph {
+                functions: vec![Function {
+                    basic_blocks: successors
+                        .iter()
+                        .enumerate()
+                        .map(|(node_index, successors)| BasicBlock {
+                            id: node_index as u64,
+                            successors: successors.clone(),
+                            sancov_index: node_sancov_map
+                                .get(&node_index)
+                                .map_or(NO_SANCOV_INDEX, |sancov_index| *sancov_index as u64),
+                        })
+                        .collect(),
+                    ..Default::default()
+                }],
+            };
+            let target = Target::from_cfg(&cfg).unwrap();
+            let mut reference_fuzzer = ReferenceFuzzer {
+                sancov_edge_dict: Target::build_sancov_edge_dict(
+                    &target.struct_graph.nodes,
+                    &node_sancov_map,
+                ),
+                bit_counters: HashMap::new(),
+                edge_bit_counters: HashMap::new(),
+            };
+            let mut fuzzer = Fuzzer::new(Arc::new(target), None);
+
+            let sancov_indices: Vec<u32> = node_sancov_map.values().copied().collect();
+            for _ in 0..4 {
+                let mut features: Vec<u32> = Vec::new();
+                for sancov_index in sancov_indices.iter() {
+                    for _ in 0..rng.below(3) {
+                        features.push(sancov_index * 8 + rng.below(8) as u32);
+                    }
+                }
+                // Features of sancov indices which aren't instrumented.
+                features.push(rng.below(u32::MAX as u64) as u32);
+                features.push(rng.below(64) as u32);
+                let features = fuzzer.decode_features(&features);
+                let coverage_update = sort_coverage_update(fuzzer.update_features(&features));
+                let reference_update =
+                    sort_coverage_update(reference_fuzzer.update_features(&features));
+                assert_eq!(coverage_update.bit_counters, reference_update.bit_counters);
+                num_hit_nodes += coverage_update.bit_counters.len();
+                assert_eq!(
+                    coverage_update.edge_bit_counters,
+                    reference_update.edge_bit_counters
                 );
             }
-            visiting_map[node_index] = NO_SANCOV_INDEX;
         }
-        path.pop();
+        assert!(num_hit_nodes > 0);
     }
 }
@@ -0,0 +1,156 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::fuzzer::{Fuzzer, Target};
+use common::observer_proto::FuzzerStats;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+// Fuzzers are spread over shards by ID, so fuzzers in different shards never
+// contend on the map.
+const NUM_SHARDS: usize = 64;
+
+/// State of a single fuzzer. Each part has its own lock, or none, so updates of
+/// a fuzzer don't block each other or the other fuzzers.
+pub struct FuzzerEntry {
+    target: Arc<Target>,
+    pub fuzzer: Mutex<Fuzzer>,
+    // Latest stats reported by the fuzzer.
+    pub stats: Mutex<FuzzerStats>,
+    created_time: Instant,
+    // Milliseconds from created_time.
+    last_active_millis: AtomicU64,
+}
+
+impl FuzzerEntry {
+    pub fn target(&self) -> &Arc<Target> {
+        &self.target
+    }
+
+    pub fn touch(&self) {
+        let active_millis = self.created_time.elapsed().as_millis() as u64;
+        self.last_active_millis
+            .fetch_max(active_millis, Ordering::Relaxed);
+    }
+
+    fn inactive_duration(&self, now: Instant) -> Duration {
+        let last_active_time = self.created_time
+            + Duration::from_millis(self.last_active_millis.load(Ordering::Relaxed));
+        now.saturating_duration_since(last_active_time)
+    }
+}
+
+/// Fuzzers keyed by ID. Lookups hand out the entry and release the map, so no
+/// map lock is held while a fuzzer is updated.
+pub struct FuzzerMap {
+    shards: Vec<RwLock<HashMap<u64, Arc<FuzzerEntry>>>>,
+    len: AtomicUsize,
+}
+
+impl Default for FuzzerMap {
+    fn default() -> Self {
+        Self {
+            shards: (0..NUM_SHARDS).map(|_| RwLock::default()).collect(),
+            len: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl FuzzerMap {
+    /// Inserts the fuzzer unless the map already holds `max_len` of them.
+    pub fn insert(&self, fuzzer_id: u64, fuzzer: Fuzzer, max_len: usize) -> bool {
+        if self.len.fetch_add(1, Ordering::Relaxed) >= max_len {
+            self.len.fetch_sub(1, Ordering::Relaxed);
+            return false;
+        }
+        let entry = FuzzerEntry {
+            target: fuzzer.target().clone(),
+            fuzzer: Mutex::new(fuzzer),
+            stats: Mutex::new(FuzzerStats::default()),
+            created_time: Instant::now(),
+            last_active_millis: AtomicU64::new(0),
+        };
+        self.shard(fuzzer_id)
+            .write()
+            .unwrap()
+            .insert(fuzzer_id, Arc::new(entry));
+        true
+    }
+
+    pub fn get(&self, fuzzer_id: u64) -> Option<Arc<FuzzerEntry>> {
+        self.shard(fuzzer_id)
+            .read()
+            .unwrap()
+            .get(&fuzzer_id)
+            .cloned()
+    }
+
+    pub fn remove(&self, fuzzer_id: u64) -> bool {
+        let removed = self
+            .shard(fuzzer_id)
+            .write()
+            .unwrap()
+            .remove(&fuzzer_id)
+            .is_some();
+        if removed {
+            self.len.fetch_sub(1, Ordering::Relaxed);
+        }
+        removed
+    }
+
+    /// IDs of the fuzzers running the target.
+    pub fn fuzzer_ids_of_target(&self, target: &Arc<Target>) -> Vec<u64> {
+        let mut fuzzer_ids = Vec::new();
+        for shard in self.shards.iter() {
+            fuzzer_ids.extend(
+                shard
+                    .read()
+                    .unwrap()
+                    .iter()
+                    .filter(|(_, entry)| Arc::ptr_eq(&entry.target, target))
+                    .map(|(fuzzer_id, _)| *fuzzer_id),
+            );
+        }
+        fuzzer_ids
+    }
+
+    /// Removes the fuzzers inactive for longer than `timeout` and returns their
+    /// IDs.
+    pub fn remove_inactive(&self, timeout: Duration) -> Vec<u64> {
+        let now = Instant::now();
+        let mut removed_fuzzer_ids = Vec::new();
+        for shard in self.shards.iter() {
+            shard.write().unwrap().retain(|fuzzer_id, entry| {
+                let inactive = entry.inactive_duration(now) > timeout;
+                if inactive {
+                    removed_fuzzer_ids.push(*fuzzer_id);
+                }
+                !inactive
+            });
+        }
+        self.len
+            .fetch_sub(removed_fuzzer_ids.len(), Ordering::Relaxed);
+        removed_fuzzer_ids
+    }
+
+    fn shard(&self, fuzzer_id: u64) -> &RwLock<HashMap<u64, Arc<FuzzerEntry>>> {
+        &self.shards[fuzzer_id as usize % NUM_SHARDS]
+    }
+}
@@ -12,35 +12,153 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod bit_counter;
+mod controller;
+mod corpus;
+mod crash;
+mod error;
 mod fuzzer;
+mod fuzzer_map;
+mod recording;
+mod target_store;
 use async_trait::async_trait;
+pub use bit_counter::BitCounter;
 use common::{
     collector_proto::{
         collector_service_server::CollectorService,
-        collector_service_server::CollectorServiceServer, ControlFlowGraph, CreateFuzzerRequest,
-        CreateFuzzerResponse, UpdateFeaturesRequest, UpdateFeaturesResponse,
+        collector_service_server::CollectorServiceServer,
+        control_command::{AddSeeds, Command, SetSeedPriorities},
+        ControlCommand, ControlFlowGraph, ControlRequest, CreateFuzzerRequest,
+        CreateFuzzerResponse, DeleteFuzzerRequest, DeleteFuzzerResponse, HeartbeatRequest,
+        HeartbeatResponse, ReportCrashRequest, ReportCrashResponse, SeedPriority,
+        UpdateFeaturesRequest, UpdateFeaturesResponse, UpdateStatsRequest, UpdateStatsResponse,
+        UploadCfgRequest, UploadCfgResponse,
     },
+    hash_cfg,
     observer_proto::{
-        structure_graph::Function as GraphFunction, structure_graph::Node as GraphNode,
-        StructureGraph,
+        structure_graph::Function as GraphFunction, structure_graph::Node as GraphNode, Crash,
+        Feature, FuzzerMetadata, FuzzerStats, StructureGraph,
     },
+    NO_SANCOV_INDEX,
 };
-use fuzzer::Fuzzer;
-use std::{collections::HashMap, sync::Mutex};
-use tonic::{Request, Response, Status};
+pub use controller::Controller;
+pub use corpus::Corpus;
+use crash::hash_crash_stack;
+pub use error::Error;
+pub use fuzzer::{CoverageUpdate, Fuzzer, Target};
+use fuzzer_map::{FuzzerEntry, FuzzerMap};
+pub use recording::{replay_recording, RecordingError, RecordingReader};
+use sha1::{Digest, Sha1};
+use std::{
+    collections::{hash_map::RandomState, HashSet},
+    hash::{BuildHasher, Hasher},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::Duration,
+};
+use target_store::TargetStore;
+use tokio::sync::mpsc;
+use tokio_stream::{
+    wrappers::{ReceiverStream, UnboundedReceiverStream},
+    Stream, StreamExt,
+};
+use tonic::{Request, Response, Status, Streaming};
+
+// Fuzzers which haven't sent anything for this long are removed.
+const FUZZER_EXPIRY_TIMEOUT: Duration = Duration::from_secs(60);
+const FUZZER_EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+const MAX_FUZZERS: usize = 1 << 16;
+// Features are 32-bit and each sancov index takes 8 of them.
+const MAX_SANCOV_INDEX: u64 = (u32::MAX / 8) as u64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RemoveReason {
+    /// The fuzzer asked to be deleted, usually when it exits normally.
+    Deleted,
+    /// The fuzzer stopped sending heartbeats and updates.
+    Expired,
+}
 
 #[async_trait]
 pub trait Observer {
-    async fn create_fuzzer(&self, fuzzer_id: u64, struct_graph: &StructureGraph);
+    async fn create_fuzzer(
+        &self,
+        fuzzer_id: u64,
+        metadata: &FuzzerMetadata,
+        struct_graph: &StructureGraph,
+    );
 
-    async fn update_features(&self, fuzzer_id: u64, bit_counters: &[(usize, u8)]);
+    /// `features` holds every feature of the update decoded by kind, including
+    /// the counters already mapped onto `bit_counters`.
+    async fn update_features(
+        &self,
+        fuzzer_id: u64,
+        bit_counters: &[(usize, BitCounter)],
+        features: &[Feature],
+    );
+
+    /// Called with the CFG edges `(src_node, dst_node, bit_counter)` hit by a
+    /// feature update, which tell the branch directions taken out of a node.
+    async fn update_edges(
+        &self,
+        _fuzzer_id: u64,
+        _edge_bit_counters: &[(usize, usize, BitCounter)],
+    ) {
+    }
+
+    /// Called after each feature update of a fuzzer to direct how often it
+    /// reports its full feature set without new coverage, e.g. less often once
+    /// its coverage plateaus or when the collector is overloaded. Returns the
+    /// average number of executions between samples, or None to keep the
+    /// current interval.
+    async fn sample_interval(&self, _fuzzer_id: u64) -> Option<u64> {
+        None
+    }
+
+    /// Called when a fuzzer adds a new seed to its corpus, with the coverage
+    /// of the seed. If the fuzzer sent its content, the seed is already in
+    /// `Controller::corpus`, unless it's too large. The returned priorities are
+    /// pushed to the fuzzer and applied to its corpus scheduling.
+    async fn add_seed(
+        &self,
+        _fuzzer_id: u64,
+        _seed_sha1: &[u8],
+        _bit_counters: &[(usize, BitCounter)],
+    ) -> Vec<SeedPriority> {
+        Vec::new()
+    }
+
+    /// Called with the stats a fuzzer sends periodically. The latest ones are
+    /// also kept in `Controller::fuzzer_stats`.
+    async fn update_stats(&self, _fuzzer_id: u64, _stats: &FuzzerStats) {}
+
+    async fn remove_fuzzer(&self, _fuzzer_id: u64, _reason: RemoveReason) {}
+
+    /// Called once for each unique crash, with `stack_hash` set. Duplicates
+    /// found later, by any fuzzer, aren't reported. Crashes without a stack,
+    /// e.g. leaks, have no hash and are always reported.
+    async fn report_crash(&self, _fuzzer_id: u64, _crash: &Crash) {}
 }
 
 pub type ObserverPtr = Box<dyn Observer + Sync + Send>;
 
+// Cheap to clone, so feature streams can be served by tasks of their own.
+#[derive(Clone)]
 pub struct CollectorServiceImpl {
-    fuzzer_map: Mutex<HashMap<u64, Fuzzer>>,
-    observer: ObserverPtr,
+    fuzzer_map: Arc<FuzzerMap>,
+    target_store: Arc<TargetStore>,
+    // IDs are never reused, so observers can't mix up removed fuzzers with
+    // new ones.
+    next_fuzzer_id: Arc<AtomicU64>,
+    // Random, so fuzzers can tell a restarted collector from the one they
+    // registered to.
+    instance_id: u64,
+    crash_stack_hashes: Arc<Mutex<HashSet<Vec<u8>>>>,
+    observer: Arc<dyn Observer + Sync + Send>,
+    controller: Controller,
 }
 
 #[async_trait]
@@ -50,49 +168,380 @@ impl CollectorService for CollectorServiceImpl {
         req: Request<CreateFuzzerRequest>,
     ) -> Result<Response<CreateFuzzerResponse>, Status> {
         let create_fuzzer_req = req.into_inner();
-        let cfg = create_fuzzer_req.cfg.unwrap();
-
-        let struct_graph = build_structure_graph(&cfg);
-        let fuzzer = Fuzzer::new(&struct_graph, cfg);
-        let fuzzer_id = {
-            let mut fuzzer_map = self.fuzzer_map.lock().unwrap();
-            let fuzzer_id = fuzzer_map.len() as u64;
-            fuzzer_map.insert(fuzzer_id, fuzzer);
-            fuzzer_id
+        let metadata = create_fuzzer_req.metadata.unwrap_or_default();
+        let target = match create_fuzzer_req.cfg {
+            Some(cfg) => {
+                let cfg_hash = hash_cfg(&cfg);
+                if !create_fuzzer_req.cfg_hash.is_empty() && create_fuzzer_req.cfg_hash != cfg_hash
+                {
+                    return Err(Error::CfgHashMismatch.into());
+                }
+                self.load_target(cfg_hash, cfg).await?
+            }
+            None if !create_fuzzer_req.cfg_hash.is_empty() => {
+                self.target_store.get(&create_fuzzer_req.cfg_hash)?
+            }
+            None => return Err(Error::MissingCfg.into()),
         };
-        self.observer.create_fuzzer(fuzzer_id, &struct_graph).await;
 
-        Ok(Response::new(CreateFuzzerResponse { id: fuzzer_id }))
+        let fuzzer = Fuzzer::new(target.clone(), create_fuzzer_req.feature_layout);
+        let fuzzer_id = self.next_fuzzer_id.fetch_add(1, Ordering::Relaxed);
+        if !self.fuzzer_map.insert(fuzzer_id, fuzzer, MAX_FUZZERS) {
+            return Err(Error::TooManyFuzzers(MAX_FUZZERS).into());
+        }
+        self.observer
+            .create_fuzzer(fuzzer_id, &metadata, target.struct_graph())
+            .await;
+
+        Ok(Response::new(CreateFuzzerResponse {
+            id: fuzzer_id,
+            collector_instance_id: self.instance_id,
+        }))
+    }
+
+    async fn upload_cfg(
+        &self,
+        req: Request<Streaming<UploadCfgRequest>>,
+    ) -> Result<Response<UploadCfgResponse>, Status> {
+        let mut stream = req.into_inner();
+        let mut cfg = ControlFlowGraph::default();
+        while let Some(upload_cfg_req) = stream.message().await? {
+            cfg.functions.extend(upload_cfg_req.functions);
+        }
+
+        let cfg_hash = hash_cfg(&cfg);
+        // The analysis of a large CFG takes minutes, so only the validation is
+        // awaited. Fuzzers can be created with the hash once it's done.
+        validate_cfg(&cfg)?;
+        self.target_store
+            .load(cfg_hash.clone(), move || Target::from_cfg(&cfg));
+
+        Ok(Response::new(UploadCfgResponse { cfg_hash }))
     }
 
     async fn update_features(
         &self,
         req: Request<UpdateFeaturesRequest>,
     ) -> Result<Response<UpdateFeaturesResponse>, Status> {
-        let update_feature_req = req.into_inner();
+        let update_features_res = self.handle_update_features(req.into_inner()).await?;
+
+        Ok(Response::new(update_features_res))
+    }
+
+    type StreamFeaturesStream =
+        Pin<Box<dyn Stream<Item = Result<UpdateFeaturesResponse, Status>> + Send + Sync + 'static>>;
+
+    async fn stream_features(
+        &self,
+        req: Request<Streaming<UpdateFeaturesRequest>>,
+    ) -> Result<Response<Self::StreamFeaturesStream>, Status> {
+        let mut stream = req.into_inner();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let service = self.clone();
+        tokio::spawn(async move {
+            // Only changes of the sampling interval are sent back.
+            let mut sample_interval = 0;
+            while let Ok(Some(update_feature_req)) = stream.message().await {
+                match service.handle_update_features(update_feature_req).await {
+                    Ok(update_features_res)
+                        if update_features_res.sample_interval != 0
+                            && update_features_res.sample_interval != sample_interval =>
+                    {
+                        sample_interval = update_features_res.sample_interval;
+                        if sender.send(Ok(update_features_res)).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(error) => {
+                        let _ = sender.send(Err(error.into()));
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(Response::new(Box::pin(UnboundedReceiverStream::new(
+            receiver,
+        ))))
+    }
+
+    async fn delete_fuzzer(
+        &self,
+        req: Request<DeleteFuzzerRequest>,
+    ) -> Result<Response<DeleteFuzzerResponse>, Status> {
+        let fuzzer_id = req.into_inner().id;
+
+        if !self.fuzzer_map.remove(fuzzer_id) {
+            return Err(Error::UnknownFuzzer(fuzzer_id).into());
+        }
+        self.observer
+            .remove_fuzzer(fuzzer_id, RemoveReason::Deleted)
+            .await;
+
+        Ok(Response::new(DeleteFuzzerResponse {}))
+    }
+
+    async fn heartbeat(
+        &self,
+        req: Request<HeartbeatRequest>,
+    ) -> Result<Response<HeartbeatResponse>, Status> {
+        let fuzzer_id = req.into_inner().id;
+
+        self.get_fuzzer(fuzzer_id)?;
+
+        Ok(Response::new(HeartbeatResponse {
+            collector_instance_id: self.instance_id,
+        }))
+    }
+
+    async fn update_stats(
+        &self,
+        req: Request<UpdateStatsRequest>,
+    ) -> Result<Response<UpdateStatsResponse>, Status> {
+        let update_stats_req = req.into_inner();
+        let fuzzer_id = update_stats_req.id;
+        let stats = update_stats_req.stats.unwrap_or_default();
+
+        *self.get_fuzzer(fuzzer_id)?.stats.lock().unwrap() = stats.clone();
+        self.observer.update_stats(fuzzer_id, &stats).await;
+
+        Ok(Response::new(UpdateStatsResponse {}))
+    }
+
+    async fn report_crash(
+        &self,
+        req: Request<ReportCrashRequest>,
+    ) -> Result<Response<ReportCrashResponse>, Status> {
+        let report_crash_req = req.into_inner();
+        let fuzzer_id = report_crash_req.id;
+        let mut crash = report_crash_req.crash.ok_or(Error::MissingCrash)?;
+
+        self.get_fuzzer(fuzzer_id)?;
+        let duplicate = match hash_crash_stack(&crash) {
+            Some(stack_hash) => {
+                crash.stack_hash = stack_hash.clone();
+                !self.crash_stack_hashes.lock().unwrap().insert(stack_hash)
+            }
+            None => false,
+        };
+        if !duplicate {
+            self.observer.report_crash(fuzzer_id, &crash).await;
+        }
+
+        Ok(Response::new(ReportCrashResponse { duplicate }))
+    }
+
+    type ControlStream =
+        Pin<Box<dyn Stream<Item = Result<ControlCommand, Status>> + Send + Sync + 'static>>;
+
+    async fn control(
+        &self,
+        req: Request<Streaming<ControlRequest>>,
+    ) -> Result<Response<Self::ControlStream>, Status> {
+        let mut stream = req.into_inner();
+        let fuzzer_id = match stream.message().await? {
+            Some(control_req) => control_req.id,
+            None => return Err(Error::MissingFuzzerId.into()),
+        };
+
+        self.get_fuzzer(fuzzer_id)?;
+        let (sender, receiver) = self.controller.register(fuzzer_id)?;
+        let controller = self.controller.clone();
+        tokio::spawn(async move {
+            // The channel lives until the fuzzer closes its side of the stream.
+            while let Ok(Some(_)) = stream.message().await {}
+            controller.unregister(fuzzer_id, &sender);
+        });
+
+        Ok(Response::new(Box::pin(
+            ReceiverStream::new(receiver).map(Ok),
+        )))
+    }
+}
+
+impl CollectorServiceImpl {
+    fn new(observer: ObserverPtr, controller: Controller) -> Self {
+        Self {
+            fuzzer_map: controller.fuzzer_map().clone(),
+            target_store: Arc::new(TargetStore::default()),
+            next_fuzzer_id: Arc::new(AtomicU64::new(0)),
+            instance_id: RandomState::new().build_hasher().finish(),
+            crash_stack_hashes: Arc::new(Mutex::new(HashSet::new())),
+            observer: Arc::from(observer),
+            controller,
+        }
+    }
+
+    // Looks up the fuzzer and marks it as active.
+    fn get_fuzzer(&self, fuzzer_id: u64) -> Result<Arc<FuzzerEntry>, Error> {
+        let entry = self
+            .fuzzer_map
+            .get(fuzzer_id)
+            .ok_or(Error::UnknownFuzzer(fuzzer_id))?;
+        entry.touch();
+        Ok(entry)
+    }
+
+    async fn load_target(
+        &self,
+        cfg_hash: Vec<u8>,
+        cfg: ControlFlowGraph,
+    ) -> Result<Arc<Target>, Error> {
+        self.target_store
+            .load(cfg_hash, move || Target::from_cfg(&cfg))
+            .wait()
+            .await
+    }
+
+    async fn handle_update_features(
+        &self,
+        update_feature_req: UpdateFeaturesRequest,
+    ) -> Result<UpdateFeaturesResponse, Error> {
         let fuzzer_id = update_feature_req.id;
         let features = update_feature_req.features;
+        let removed_features = update_feature_req.removed_features;
+        let delta = update_feature_req.delta;
+        let delta_base = update_feature_req.delta_base;
+        let seed_sha1 = update_feature_req.seed_sha1;
+        let seed = update_feature_req.seed;
+        if !seed.is_empty() && Sha1::digest(&seed)[..] != seed_sha1[..] {
+            return Err(Error::SeedSha1Mismatch);
+        }
 
-        let hit_bit_counters = self
-            .fuzzer_map
-            .lock()
-            .unwrap()
-            .get_mut(&fuzzer_id)
-            .unwrap()
-            .update_features(&features);
+        let entry = self.get_fuzzer(fuzzer_id)?;
+        // Only this fuzzer is locked, and not across the observer calls below.
+        let (coverage_update, features) = {
+            let mut fuzzer = entry.fuzzer.lock().unwrap();
+            let features = fuzzer.decode_update(features, removed_features, delta, delta_base);
+            (fuzzer.update_features(&features), features)
+        };
         self.observer
-            .update_features(fuzzer_id, &hit_bit_counters)
+            .update_features(fuzzer_id, &coverage_update.bit_counters, &features)
             .await;
+        if !coverage_update.edge_bit_counters.is_empty() {
+            self.observer
+                .update_edges(fuzzer_id, &coverage_update.edge_bit_counters)
+                .await;
+        }
+
+        if !seed_sha1.is_empty() {
+            if !seed.is_empty()
+                && self
+                    .controller
+                    .corpus()
+                    .insert(seed_sha1.clone(), seed.clone())
+            {
+                self.redistribute_seed(fuzzer_id, seed);
+            }
+            let priorities = self
+                .observer
+                .add_seed(fuzzer_id, &seed_sha1, &coverage_update.bit_counters)
+                .await;
+            if !priorities.is_empty() {
+                self.controller.send_command(
+                    fuzzer_id,
+                    Command::SetSeedPriorities(SetSeedPriorities { priorities }),
+                );
+            }
+        }
+        let sample_interval = self.observer.sample_interval(fuzzer_id).await.unwrap_or(0);
+        Ok(UpdateFeaturesResponse { sample_interval })
+    }
+
+    // Sends a seed new to the corpus to the other fuzzers of the same target.
+    fn redistribute_seed(&self, source_fuzzer_id: u64, seed: Vec<u8>) {
+        let target = match self.fuzzer_map.get(source_fuzzer_id) {
+            Some(entry) => entry.target().clone(),
+            None => return,
+        };
+        for fuzzer_id in self.fuzzer_map.fuzzer_ids_of_target(&target) {
+            if fuzzer_id == source_fuzzer_id {
+                continue;
+            }
+            self.controller.send_command(
+                fuzzer_id,
+                Command::AddSeeds(AddSeeds {
+                    seeds: vec![seed.clone()],
+                }),
+            );
+        }
+    }
+}
+
+/// Creates the collector service. It must be called within a Tokio runtime,
+/// which runs the expiry of silent fuzzers in the background.
+pub fn create_service(
+    observer: ObserverPtr,
+    controller: Controller,
+) -> CollectorServiceServer<CollectorServiceImpl> {
+    let service = CollectorServiceImpl::new(observer, controller);
+    tokio::spawn(expire_fuzzers(
+        Arc::downgrade(&service.fuzzer_map),
+        service.observer.clone(),
+    ));
+    CollectorServiceServer::new(service)
+}
 
-        Ok(Response::new(UpdateFeaturesResponse {}))
+async fn expire_fuzzers(fuzzer_map: Weak<FuzzerMap>, observer: Arc<dyn Observer + Sync + Send>) {
+    let mut interval = tokio::time::interval(FUZZER_EXPIRY_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        // Stop once the service and its controllers have been dropped.
+        let fuzzer_map = match fuzzer_map.upgrade() {
+            Some(fuzzer_map) => fuzzer_map,
+            None => break,
+        };
+        let expired_fuzzer_ids = fuzzer_map.remove_inactive(FUZZER_EXPIRY_TIMEOUT);
+        for fuzzer_id in expired_fuzzer_ids {
+            observer
+                .remove_fuzzer(fuzzer_id, RemoveReason::Expired)
+                .await;
+        }
     }
 }
 
-pub fn create_service(observer: ObserverPtr) -> CollectorServiceServer<CollectorServiceImpl> {
-    CollectorServiceServer::new(CollectorServiceImpl {
-        fuzzer_map: Mutex::new(HashMap::new()),
-        observer,
-    })
+// Checks the invariants `build_structure_graph` and `Fuzzer` rely on to index
+// nodes by block ID.
+fn validate_cfg(cfg: &ControlFlowGraph) -> Result<(), Error> {
+    let cfg_blocks = || {
+        cfg.functions
+            .iter()
+            .flat_map(|cfg_function| cfg_function.basic_blocks.iter())
+    };
+    let num_blocks = cfg_blocks().count();
+    let mut block_ids = HashSet::new();
+    for cfg_block in cfg_blocks() {
+        if cfg_block.id >= num_blocks as u64 {
+            return Err(Error::BlockIdOutOfRange {
+                block_id: cfg_block.id,
+                num_blocks,
+            });
+        }
+        if !block_ids.insert(cfg_block.id) {
+            return Err(Error::DuplicateBlockId(cfg_block.id));
+        }
+        if cfg_block.sancov_index != NO_SANCOV_INDEX && cfg_block.sancov_index > MAX_SANCOV_INDEX {
+            return Err(Error::SancovIndexOutOfRange {
+                block_id: cfg_block.id,
+                sancov_index: cfg_block.sancov_index,
+            });
+        }
+    }
+    // Block IDs are unique and in range, so every ID in range is a block.
+    for cfg_block in cfg_blocks() {
+        if let Some(successor) = cfg_block
+            .successors
+            .iter()
+            .find(|successor| **successor >= num_blocks as u64)
+        {
+            return Err(Error::DanglingSuccessor {
+                block_id: cfg_block.id,
+                successor: *successor,
+            });
+        }
+    }
+    Ok(())
 }
 
 fn build_structure_graph(cfg: &ControlFlowGraph) -> StructureGraph {
@@ -134,3 +583,111 @@ fn build_structure_graph(cfg: &ControlFlowGraph) -> StructureGraph {
     }
     StructureGraph { nodes, functions }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::collector_proto::control_flow_graph::{BasicBlock, Function};
+    use tonic::Code;
+
+    struct NullObserver;
+
+    #[async_trait]
+    impl Observer for NullObserver {
+        async fn create_fuzzer(
+            &self,
+            _fuzzer_id: u64,
+            _metadata: &FuzzerMetadata,
+            _struct_graph: &StructureGraph,
+        ) {
+        }
+
+        async fn update_features(
+            &self,
+            _fuzzer_id: u64,
+            _bit_counters: &[(usize, BitCounter)],
+            _features: &[Feature],
+        ) {
+        }
+    }
+
+    fn build_cfg(blocks: &[(u64, &[u64], u64)]) -> ControlFlowGraph {
+        ControlFlowGraph {
+            functions: vec![Function {
+                id: 0,
+                name: "main".to_owned(),
+                basic_blocks: blocks
+                    .iter()
+                    .map(|(id, successors, sancov_index)| BasicBlock {
+                        id: *id,
+                        successors: successors.to_vec(),
+                        sancov_index: *sancov_index,
+                    })
+                    .collect(),
+            }],
+        }
+    }
+
+    // Checks the CFG is rejected with the error, and that creating a fuzzer
+    // with it fails with INVALID_ARGUMENT.
+    fn assert_rejected(cfg: ControlFlowGraph, error: Error) {
+        assert_eq!(validate_cfg(&cfg), Err(error));
+        let service = CollectorServiceImpl::new(Box::new(NullObserver), Controller::new());
+        let status = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(service.create_fuzzer(Request::new(CreateFuzzerRequest {
+                cfg: Some(cfg),
+                ..Default::default()
+            })))
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+
+    #[test]
+    fn accepts_valid_cfg() {
+        let cfg = build_cfg(&[(1, &[0, 1], NO_SANCOV_INDEX), (0, &[1], MAX_SANCOV_INDEX)]);
+        assert_eq!(validate_cfg(&cfg), Ok(()));
+    }
+
+    #[test]
+    fn rejects_duplicate_block_ids() {
+        assert_rejected(
+            build_cfg(&[(0, &[], 0), (1, &[], 1), (1, &[], 2)]),
+            Error::DuplicateBlockId(1),
+        );
+    }
+
+    #[test]
+    fn rejects_out_of_range_block_ids() {
+        assert_rejected(
+            build_cfg(&[(0, &[], 0), (2, &[], 1)]),
+            Error::BlockIdOutOfRange {
+                block_id: 2,
+                num_blocks: 2,
+            },
+        );
+    }
+
+    #[test]
+    fn rejects_dangling_successors() {
+        assert_rejected(
+            build_cfg(&[(0, &[1], 0), (1, &[0, 2], 1)]),
+            Error::DanglingSuccessor {
+                block_id: 1,
+                successor: 2,
+            },
+        );
+    }
+
+    #[test]
+    fn rejects_out_of_range_sancov_indices() {
+        assert_rejected(
+            build_cfg(&[(0, &[], 0), (1, &[], MAX_SANCOV_INDEX + 1)]),
+            Error::SancovIndexOutOfRange {
+                block_id: 1,
+                sancov_index: MAX_SANCOV_INDEX + 1,
+            },
+        );
+    }
+}
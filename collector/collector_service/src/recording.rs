@@ -0,0 +1,412 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{CollectorServiceImpl, Controller, Error, ObserverPtr};
+use common::{
+    collector_proto::{
+        collector_service_server::CollectorService, recorded_event::Event, DeleteFuzzerRequest,
+        RecordedEvent,
+    },
+    RECORDING_MAGIC,
+};
+use prost::{DecodeError, Message};
+use std::{fmt, io, io::Read};
+use tonic::{Request, Status};
+
+// Longest varint encoding of a u64.
+const MAX_VARINT_SIZE: usize = 10;
+// The largest event registers the fuzzer with its full CFG. Longer lengths are
+// corrupt, and aren't allocated.
+const MAX_EVENT_SIZE: u64 = 1 << 30;
+
+#[derive(Debug)]
+pub enum RecordingError {
+    Io(io::Error),
+    /// The file doesn't start with `RECORDING_MAGIC`.
+    NotARecording,
+    InvalidLength,
+    Decode(DecodeError),
+    /// The first event doesn't register the fuzzer.
+    MissingCreateFuzzer,
+    /// The collector rejected a recorded request, with the reason.
+    Rejected(String),
+}
+
+impl fmt::Display for RecordingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordingError::Io(error) => write!(f, "Failed to read the recording: {}", error),
+            RecordingError::NotARecording => write!(f, "Not a recording file."),
+            RecordingError::InvalidLength => write!(f, "Invalid event length."),
+            RecordingError::Decode(error) => write!(f, "Failed to decode an event: {}", error),
+            RecordingError::MissingCreateFuzzer => {
+                write!(f, "Recording doesn't start with the fuzzer registration.")
+            }
+            RecordingError::Rejected(message) => {
+                write!(f, "Recorded request rejected: {}", message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RecordingError {}
+
+impl From<io::Error> for RecordingError {
+    fn from(error: io::Error) -> Self {
+        RecordingError::Io(error)
+    }
+}
+
+impl From<DecodeError> for RecordingError {
+    fn from(error: DecodeError) -> Self {
+        RecordingError::Decode(error)
+    }
+}
+
+impl From<Status> for RecordingError {
+    fn from(status: Status) -> Self {
+        RecordingError::Rejected(status.message().to_owned())
+    }
+}
+
+impl From<Error> for RecordingError {
+    fn from(error: Error) -> Self {
+        RecordingError::Rejected(error.to_string())
+    }
+}
+
+/// Reads the events of a recording written by the fuzzer client.
+pub struct RecordingReader<R> {
+    reader: R,
+    buffer: Vec<u8>,
+}
+
+impl<R: Read> RecordingReader<R> {
+    pub fn new(mut reader: R) -> Result<Self, RecordingError> {
+        let mut magic = [0; RECORDING_MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if magic != RECORDING_MAGIC {
+            return Err(RecordingError::NotARecording);
+        }
+        Ok(Self {
+            reader,
+            buffer: Vec::new(),
+        })
+    }
+
+    /// Returns None at the end of the recording.
+    pub fn next_event(&mut self) -> Result<Option<RecordedEvent>, RecordingError> {
+        let length = match self.read_length()? {
+            Some(length) => length,
+            None => return Ok(None),
+        };
+        if length > MAX_EVENT_SIZE {
+            return Err(RecordingError::InvalidLength);
+        }
+        self.buffer.clear();
+        // The buffer only grows as the event is read, so a length beyond the
+        // end of the file doesn't allocate it.
+        let read_size = (&mut self.reader)
+            .take(length)
+            .read_to_end(&mut self.buffer)?;
+        if (read_size as u64) < length {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        Ok(Some(RecordedEvent::decode(&self.buffer[..])?))
+    }
+
+    fn read_length(&mut self) -> Result<Option<u64>, RecordingError> {
+        let mut length = 0;
+        for index in 0..MAX_VARINT_SIZE {
+            let mut byte = [0];
+            if self.reader.read(&mut byte)? == 0 {
+                if index == 0 {
+                    return Ok(None);
+                }
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            length |= ((byte[0] & 0x7f) as u64) << (7 * index);
+            if byte[0] & 0x80 == 0 {
+                return Ok(Some(length));
+            }
+        }
+        Err(RecordingError::InvalidLength)
+    }
+}
+
+/// Replays a recording into the observer, as if the fuzzer had been connected
+/// to a collector, and removes the fuzzer at the end. The recording is read
+/// synchronously, so use a reader which doesn't block for long.
+pub async fn replay_recording<R: Read>(
+    reader: R,
+    observer: ObserverPtr,
+) -> Result<(), RecordingError> {
+    let service = CollectorServiceImpl::new(observer, Controller::new());
+    let mut reader = RecordingReader::new(reader)?;
+    let create_fuzzer_req = match reader.next_event()?.and_then(|event| event.event) {
+        Some(Event::CreateFuzzer(create_fuzzer_req)) => create_fuzzer_req,
+        _ => return Err(RecordingError::MissingCreateFuzzer),
+    };
+    let fuzzer_id = service
+        .create_fuzzer(Request::new(create_fuzzer_req))
+        .await?
+        .into_inner()
+        .id;
+
+    loop {
+        let event = match reader.next_event() {
+            Ok(Some(event)) => event,
+            Ok(None) => break,
+            // A fuzzer killed while recording may leave its last event cut off.
+            Err(RecordingError::Io(error)) if error.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(error) => return Err(error),
+        };
+        if let Some(Event::UpdateFeatures(mut update_features_req)) = event.event {
+            update_features_req.id = fuzzer_id;
+            service.handle_update_features(update_features_req).await?;
+        }
+    }
+
+    service
+        .delete_fuzzer(Request::new(DeleteFuzzerRequest { id: fuzzer_id }))
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BitCounter, Observer, RemoveReason};
+    use async_trait::async_trait;
+    use common::{
+        collector_proto::{
+            control_flow_graph::{BasicBlock, Function},
+            ControlFlowGraph, CreateFuzzerRequest, UpdateFeaturesRequest,
+        },
+        hash_cfg,
+        observer_proto::{Feature, FuzzerMetadata, StructureGraph},
+        RecordingWriter, NO_SANCOV_INDEX,
+    };
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, PartialEq)]
+    enum ObservedCall {
+        CreateFuzzer { num_nodes: usize },
+        UpdateFeatures { nodes: Vec<usize> },
+        RemoveFuzzer(RemoveReason),
+    }
+
+    struct TestObserver {
+        calls: Arc<Mutex<Vec<ObservedCall>>>,
+    }
+
+    #[async_trait]
+    impl Observer for TestObserver {
+        async fn create_fuzzer(
+            &self,
+            _fuzzer_id: u64,
+            _metadata: &FuzzerMetadata,
+            struct_graph: &StructureGraph,
+        ) {
+            self.calls.lock().unwrap().push(ObservedCall::CreateFuzzer {
+                num_nodes: struct_graph.nodes.len(),
+            });
+        }
+
+        async fn update_features(
+            &self,
+            _fuzzer_id: u64,
+            bit_counters: &[(usize, BitCounter)],
+            _features: &[Feature],
+        ) {
+            let mut nodes: Vec<usize> = bit_counters.iter().map(|(node, _)| *node).collect();
+            nodes.sort_unstable();
+            self.calls
+                .lock()
+                .unwrap()
+                .push(ObservedCall::UpdateFeatures { nodes });
+        }
+
+        async fn remove_fuzzer(&self, _fuzzer_id: u64, reason: RemoveReason) {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(ObservedCall::RemoveFuzzer(reason));
+        }
+    }
+
+    fn record(events: &[Event]) -> Vec<u8> {
+        let mut writer = RecordingWriter::new(Vec::new()).unwrap();
+        for event in events {
+            writer.write_event(event.clone()).unwrap();
+        }
+        writer.flush().unwrap();
+        writer.into_inner()
+    }
+
+    fn recorded_events() -> Vec<Event> {
+        vec![
+            Event::CreateFuzzer(CreateFuzzerRequest {
+                cfg_hash: vec![1, 2, 3],
+                ..Default::default()
+            }),
+            Event::UpdateFeatures(UpdateFeaturesRequest {
+                features: vec![8, 9, 1 << 20],
+                ..Default::default()
+            }),
+            // Large enough for a multi-byte length.
+            Event::UpdateFeatures(UpdateFeaturesRequest {
+                features: (0..1000).collect(),
+                seed: vec![0xff; 300],
+                ..Default::default()
+            }),
+        ]
+    }
+
+    fn read_events(recording: &[u8]) -> (Vec<Event>, Result<(), RecordingError>) {
+        let mut reader = RecordingReader::new(recording).unwrap();
+        let mut events = Vec::new();
+        loop {
+            match reader.next_event() {
+                Ok(Some(recorded_event)) => {
+                    assert_ne!(recorded_event.timestamp_micros, 0);
+                    events.push(recorded_event.event.unwrap());
+                }
+                Ok(None) => return (events, Ok(())),
+                Err(error) => return (events, Err(error)),
+            }
+        }
+    }
+
+    #[test]
+    fn reads_written_events() {
+        let events = recorded_events();
+        let (read_events, result) = read_events(&record(&events));
+        assert!(result.is_ok());
+        assert_eq!(read_events, events);
+    }
+
+    #[test]
+    fn truncated_tail_ends_with_eof() {
+        let events = recorded_events();
+        let recording = record(&events);
+        let last_event_len = record(&events[2..]).len() - RECORDING_MAGIC.len();
+        // Cut inside the last event and inside its length.
+        for truncated_len in [1, last_event_len - 1, last_event_len - 2].iter() {
+            let (read_events, result) = read_events(&recording[..recording.len() - truncated_len]);
+            assert_eq!(read_events, events[..2]);
+            match result {
+                Err(RecordingError::Io(error)) => {
+                    assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof)
+                }
+                result => panic!("Unexpected result {:?}", result),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(matches!(
+            RecordingReader::new(&b"FUZVREC0"[..]),
+            Err(RecordingError::NotARecording)
+        ));
+        assert!(matches!(
+            RecordingReader::new(&b"FUZ"[..]),
+            Err(RecordingError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_oversized_lengths() {
+        let mut recording = RECORDING_MAGIC.to_vec();
+        // Longer than any event, but within a u64.
+        recording.extend_from_slice(&[0xff; 9]);
+        recording.push(0x01);
+        let mut reader = RecordingReader::new(&recording[..]).unwrap();
+        assert!(matches!(
+            reader.next_event(),
+            Err(RecordingError::InvalidLength)
+        ));
+
+        // A length beyond the end of the file is cut off rather than allocated.
+        let mut recording = RECORDING_MAGIC.to_vec();
+        recording.extend_from_slice(&[0x80, 0x80, 0x80, 0x80, 0x01]);
+        recording.extend_from_slice(&[0; 16]);
+        let mut reader = RecordingReader::new(&recording[..]).unwrap();
+        match reader.next_event() {
+            Err(RecordingError::Io(error)) => {
+                assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof)
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn replays_into_observer() {
+        // Block 1 is only reached through the uninstrumented block 2.
+        let block = |id, successors: Vec<u64>, sancov_index| BasicBlock {
+            id,
+            successors,
+            sancov_index,
+        };
+        let cfg = ControlFlowGraph {
+            functions: vec![Function {
+                id: 0,
+                name: "main".to_owned(),
+                basic_blocks: vec![
+                    block(0, vec![2], 0),
+                    block(1, vec![], 1),
+                    block(2, vec![1], NO_SANCOV_INDEX),
+                ],
+            }],
+        };
+        let recording = record(&[
+            Event::CreateFuzzer(CreateFuzzerRequest {
+                cfg_hash: hash_cfg(&cfg),
+                cfg: Some(cfg),
+                ..Default::default()
+            }),
+            Event::UpdateFeatures(UpdateFeaturesRequest {
+                features: vec![0],
+                ..Default::default()
+            }),
+            Event::UpdateFeatures(UpdateFeaturesRequest {
+                features: vec![0, 8],
+                ..Default::default()
+            }),
+        ]);
+
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let observer = Box::new(TestObserver {
+            calls: calls.clone(),
+        });
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(replay_recording(&recording[..], observer))
+            .unwrap();
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                ObservedCall::CreateFuzzer { num_nodes: 3 },
+                // Nodes are only inferred from the paths between hit blocks.
+                ObservedCall::UpdateFeatures { nodes: vec![] },
+                ObservedCall::UpdateFeatures {
+                    nodes: vec![0, 1, 2]
+                },
+                ObservedCall::RemoveFuzzer(RemoveReason::Deleted),
+            ]
+        );
+    }
+}
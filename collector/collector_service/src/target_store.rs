@@ -0,0 +1,223 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{error::Error, fuzzer::Target};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::watch;
+
+// Unused targets are kept for a while, so a fuzzer can create itself with the
+// hash of a CFG it has just uploaded.
+const UNUSED_TARGET_RETENTION: Duration = Duration::from_secs(600);
+
+// None until the analysis finishes.
+type LoadResult = Option<Result<Arc<Target>, Error>>;
+
+struct StoredTarget {
+    target: watch::Receiver<LoadResult>,
+    last_used_time: Instant,
+}
+
+impl StoredTarget {
+    fn is_retained(&self, now: Instant) -> bool {
+        match &*self.target.borrow() {
+            Some(Ok(target)) => {
+                Arc::strong_count(target) > 1
+                    || now.duration_since(self.last_used_time) < UNUSED_TARGET_RETENTION
+            }
+            // Failed loads are dropped, so they are retried.
+            Some(Err(_)) => false,
+            None => true,
+        }
+    }
+}
+
+/// Target being analyzed in the background.
+pub struct PendingTarget {
+    target: watch::Receiver<LoadResult>,
+}
+
+impl PendingTarget {
+    /// Waits until the analysis finishes. Dropping the future doesn't cancel
+    /// the analysis.
+    pub async fn wait(mut self) -> Result<Arc<Target>, Error> {
+        loop {
+            if let Some(result) = &*self.target.borrow() {
+                return result.clone();
+            }
+            // The sender is only dropped without a result if the runtime shuts
+            // down.
+            if self.target.changed().await.is_err() {
+                return Err(Error::AnalysisFailed);
+            }
+        }
+    }
+}
+
+/// Analyzed targets keyed by the hash of their CFG, so fuzzers running the
+/// same target share one analysis and only need to upload the CFG once.
+///
+/// Targets no fuzzer runs anymore are dropped after a retention period.
+#[derive(Default)]
+pub struct TargetStore {
+    targets: Mutex<HashMap<Vec<u8>, StoredTarget>>,
+}
+
+impl TargetStore {
+    /// Returns the stored target with the hash. Fails with `CfgNotReady` if it
+    /// is still being analyzed.
+    pub fn get(&self, cfg_hash: &[u8]) -> Result<Arc<Target>, Error> {
+        let mut targets = self.targets.lock().unwrap();
+        let stored_target = targets.get_mut(cfg_hash).ok_or(Error::UnknownCfg)?;
+        let target = match &*stored_target.target.borrow() {
+            Some(result) => result.clone()?,
+            None => return Err(Error::CfgNotReady),
+        };
+        stored_target.last_used_time = Instant::now();
+        Ok(target)
+    }
+
+    /// Starts analyzing the target with `analyze` on a blocking thread, unless
+    /// the hash is stored or being analyzed already. The analysis runs to the
+    /// end even if nobody waits for it, so each CFG is only analyzed once. It
+    /// must be called within a Tokio runtime.
+    pub fn load<F>(&self, cfg_hash: Vec<u8>, analyze: F) -> PendingTarget
+    where
+        F: FnOnce() -> Result<Target, Error> + Send + 'static,
+    {
+        let mut targets = self.targets.lock().unwrap();
+        let now = Instant::now();
+        targets.retain(|_, stored_target| stored_target.is_retained(now));
+        let stored_target = targets.entry(cfg_hash).or_insert_with(|| {
+            let (sender, receiver) = watch::channel(None);
+            tokio::spawn(async move {
+                let result = match tokio::task::spawn_blocking(analyze).await {
+                    Ok(result) => result.map(Arc::new),
+                    // The analysis panicked.
+                    Err(_) => Err(Error::AnalysisFailed),
+                };
+                let _ = sender.send(Some(result));
+            });
+            StoredTarget {
+                target: receiver,
+                last_used_time: now,
+            }
+        });
+        stored_target.last_used_time = now;
+        PendingTarget {
+            target: stored_target.target.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::collector_proto::ControlFlowGraph;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc,
+    };
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+    }
+
+    fn analyze() -> Result<Target, Error> {
+        Target::from_cfg(&ControlFlowGraph::default())
+    }
+
+    #[test]
+    fn concurrent_loads_analyze_once() {
+        let target_store = TargetStore::default();
+        let num_loads = Arc::new(AtomicUsize::new(0));
+        let (started_sender, started_receiver) = mpsc::channel();
+        let (resume_sender, resume_receiver) = mpsc::channel::<()>();
+        let (target, other_target) = runtime().block_on(async {
+            let pending_target = {
+                let num_loads = num_loads.clone();
+                target_store.load(b"hash".to_vec(), move || {
+                    num_loads.fetch_add(1, Ordering::Relaxed);
+                    started_sender.send(()).unwrap();
+                    // Holds the analysis until the other load has started.
+                    resume_receiver.recv().unwrap();
+                    analyze()
+                })
+            };
+            tokio::task::spawn_blocking(move || started_receiver.recv().unwrap())
+                .await
+                .unwrap();
+            assert!(matches!(target_store.get(b"hash"), Err(Error::CfgNotReady)));
+            let other_pending_target = {
+                let num_loads = num_loads.clone();
+                target_store.load(b"hash".to_vec(), move || {
+                    num_loads.fetch_add(1, Ordering::Relaxed);
+                    analyze()
+                })
+            };
+            resume_sender.send(()).unwrap();
+            (
+                pending_target.wait().await.unwrap(),
+                other_pending_target.wait().await.unwrap(),
+            )
+        });
+        assert!(Arc::ptr_eq(&target, &other_target));
+        assert_eq!(num_loads.load(Ordering::Relaxed), 1);
+        assert!(Arc::ptr_eq(&target_store.get(b"hash").unwrap(), &target));
+    }
+
+    #[test]
+    fn analysis_outlives_its_waiters() {
+        let target_store = TargetStore::default();
+        runtime().block_on(async {
+            drop(target_store.load(b"hash".to_vec(), analyze));
+            // A new waiter gets the result of the same analysis.
+            target_store
+                .load(b"hash".to_vec(), || panic!("Analyzed twice."))
+                .wait()
+                .await
+                .unwrap();
+        });
+        assert!(target_store.get(b"hash").is_ok());
+    }
+
+    #[test]
+    fn failed_load_is_retried() {
+        let target_store = TargetStore::default();
+        runtime().block_on(async {
+            let result = target_store
+                .load(b"hash".to_vec(), || Err(Error::MissingCfg))
+                .wait()
+                .await;
+            assert!(matches!(result, Err(Error::MissingCfg)));
+            let result = target_store
+                .load(b"hash".to_vec(), || panic!("Analysis panicked."))
+                .wait()
+                .await;
+            assert!(matches!(result, Err(Error::AnalysisFailed)));
+            target_store
+                .load(b"hash".to_vec(), analyze)
+                .wait()
+                .await
+                .unwrap();
+        });
+        assert!(target_store.get(b"hash").is_ok());
+        assert!(matches!(target_store.get(b"other"), Err(Error::UnknownCfg)));
+    }
+}
@@ -13,10 +13,26 @@
 // limitations under the License.
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=../../proto");
+    // The collector protocol reuses the observer messages instead of generating
+    // its own copies. It is compiled first, otherwise the empty observer module
+    // it emits would overwrite the one generated below.
+    tonic_build::configure()
+        .extern_path(".observer", "crate::observer_proto")
+        .compile(
+            &[
+                "../../proto/control_flow_graph.proto",
+                "../../proto/collector_service.proto",
+                "../../proto/recording.proto",
+            ],
+            &["../../proto"],
+        )?;
     tonic_build::configure().compile(
         &[
-            "../../proto/control_flow_graph.proto",
-            "../../proto/collector_service.proto",
+            "../../proto/crash.proto",
+            "../../proto/feature.proto",
+            "../../proto/fuzzer_metadata.proto",
+            "../../proto/fuzzer_stats.proto",
             "../../proto/structure_graph.proto",
             "../../proto/observer_service.proto",
         ],
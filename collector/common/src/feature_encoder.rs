@@ -0,0 +1,154 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::collector_proto::UpdateFeaturesRequest;
+use std::{cmp::Ordering, mem};
+
+// One of this many updates is sent in full even if its delta is smaller, so the
+// collector recovers if it ever gets out of sync.
+const FULL_SNAPSHOT_INTERVAL: u64 = 256;
+
+/// Encodes the updates of a feature stream as the changes since the previous
+/// update whenever that's smaller than the full feature set.
+#[derive(Default)]
+pub struct FeatureEncoder {
+    // Sorted features of the last encoded update.
+    last_features: Vec<u32>,
+    num_encoded: u64,
+}
+
+impl FeatureEncoder {
+    /// Encodes the update in place. Each stream needs a new encoder, and the
+    /// updates must be sent in the order they're encoded.
+    pub fn encode(&mut self, req: &mut UpdateFeaturesRequest) {
+        let mut features = mem::take(&mut req.features);
+        features.sort_unstable();
+        features.dedup();
+        let (added_features, removed_features) = diff_sorted(&self.last_features, &features);
+        if self.num_encoded % FULL_SNAPSHOT_INTERVAL != 0
+            && added_features.len() + removed_features.len() < features.len()
+        {
+            req.features = added_features;
+            req.removed_features = removed_features;
+            req.delta = true;
+        } else {
+            req.features = features.clone();
+            req.delta_base = true;
+        }
+        self.last_features = features;
+        self.num_encoded += 1;
+    }
+
+    /// Turns the last encoded update back into a full update, e.g. to keep it
+    /// once it couldn't be sent.
+    pub fn restore_last(&self, req: &mut UpdateFeaturesRequest) {
+        req.features = self.last_features.clone();
+        req.removed_features.clear();
+        req.delta = false;
+        req.delta_base = false;
+    }
+}
+
+// Returns the features only in `to` and the ones only in `from`.
+pub(crate) fn diff_sorted(from: &[u32], to: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
+    let (mut from_index, mut to_index) = (0, 0);
+    while from_index < from.len() && to_index < to.len() {
+        match from[from_index].cmp(&to[to_index]) {
+            Ordering::Less => {
+                removed.push(from[from_index]);
+                from_index += 1;
+            }
+            Ordering::Greater => {
+                added.push(to[to_index]);
+                to_index += 1;
+            }
+            Ordering::Equal => {
+                from_index += 1;
+                to_index += 1;
+            }
+        }
+    }
+    removed.extend_from_slice(&from[from_index..]);
+    added.extend_from_slice(&to[to_index..]);
+    (added, removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_sorted_finds_added_and_removed() {
+        assert_eq!(diff_sorted(&[], &[]), (vec![], vec![]));
+        assert_eq!(diff_sorted(&[], &[1, 2]), (vec![1, 2], vec![]));
+        assert_eq!(diff_sorted(&[1, 2], &[]), (vec![], vec![1, 2]));
+        assert_eq!(
+            diff_sorted(&[1, 3, 5, 7], &[2, 3, 4, 7, 8, 9]),
+            (vec![2, 4, 8, 9], vec![1, 5])
+        );
+        assert_eq!(diff_sorted(&[1, 2, 3], &[1, 2, 3]), (vec![], vec![]));
+    }
+
+    #[test]
+    fn encodes_small_changes_as_deltas() {
+        let mut encoder = FeatureEncoder::default();
+        let mut req = UpdateFeaturesRequest {
+            features: vec![5, 1, 3, 1, 7],
+            ..Default::default()
+        };
+        encoder.encode(&mut req);
+        assert!(!req.delta && req.delta_base);
+        assert_eq!(req.features, [1, 3, 5, 7]);
+
+        let mut req = UpdateFeaturesRequest {
+            features: vec![1, 3, 5, 8],
+            ..Default::default()
+        };
+        encoder.encode(&mut req);
+        assert!(req.delta && !req.delta_base);
+        assert_eq!(req.features, [8]);
+        assert_eq!(req.removed_features, [7]);
+
+        // Sent in full, as the delta wouldn't be smaller.
+        let mut req = UpdateFeaturesRequest {
+            features: vec![2],
+            ..Default::default()
+        };
+        encoder.encode(&mut req);
+        assert!(!req.delta && req.delta_base);
+        assert_eq!(req.features, [2]);
+        assert!(req.removed_features.is_empty());
+    }
+
+    #[test]
+    fn restores_last_update_in_full() {
+        let mut encoder = FeatureEncoder::default();
+        encoder.encode(&mut UpdateFeaturesRequest {
+            features: vec![1, 2, 3],
+            ..Default::default()
+        });
+        let mut req = UpdateFeaturesRequest {
+            features: vec![1, 2, 4],
+            ..Default::default()
+        };
+        encoder.encode(&mut req);
+        assert!(req.delta);
+        encoder.restore_last(&mut req);
+        assert!(!req.delta && !req.delta_base);
+        assert_eq!(req.features, [1, 2, 4]);
+        assert!(req.removed_features.is_empty());
+    }
+}
@@ -12,6 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod feature_encoder;
+mod recording;
+
+use prost::Message;
+use sha2::{Digest, Sha256};
+use std::ops::RangeInclusive;
+
+pub use feature_encoder::FeatureEncoder;
+pub use recording::{RecordingWriter, RECORDING_MAGIC};
+
 pub mod collector_proto {
     tonic::include_proto!("collector");
 }
@@ -20,4 +30,28 @@ pub mod observer_proto {
     tonic::include_proto!("observer");
 }
 
-pub const NO_SANCOV_INDEX: u64 = std::u64::MAX;
+pub const NO_SANCOV_INDEX: u64 = u64::MAX;
+
+impl observer_proto::HitCountBucket {
+    /// Hit counts of a counter which fall into the bucket.
+    pub fn hit_counts(self) -> RangeInclusive<u8> {
+        use observer_proto::HitCountBucket::*;
+        match self {
+            Hits1 => 1..=1,
+            Hits2 => 2..=2,
+            Hits3 => 3..=3,
+            Hits4To7 => 4..=7,
+            Hits8To15 => 8..=15,
+            Hits16To31 => 16..=31,
+            Hits32To127 => 32..=127,
+            Hits128OrMore => 128..=u8::MAX,
+        }
+    }
+}
+
+/// Hashes the encoded CFG, which identifies it in the collector's CFG store.
+pub fn hash_cfg(cfg: &collector_proto::ControlFlowGraph) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(cfg.encoded_len());
+    cfg.encode(&mut payload).unwrap();
+    Sha256::digest(&payload).to_vec()
+}
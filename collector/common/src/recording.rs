@@ -0,0 +1,57 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::collector_proto::{recorded_event::Event, RecordedEvent};
+use prost::Message;
+use std::{io, io::Write, time::SystemTime};
+
+/// Starts a recording file, see `recording.proto` for the format.
+pub const RECORDING_MAGIC: &[u8] = b"FUZVREC1";
+
+/// Writes the events of a fuzzer to a recording.
+pub struct RecordingWriter<W> {
+    writer: W,
+}
+
+impl<W: Write> RecordingWriter<W> {
+    /// Starts the recording by writing its magic.
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(RECORDING_MAGIC)?;
+        Ok(Self { writer })
+    }
+
+    /// Writes the event, stamped with the current time.
+    pub fn write_event(&mut self, event: Event) -> io::Result<()> {
+        let timestamp_micros = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|duration| duration.as_micros() as u64)
+            .unwrap_or(0);
+        let recorded_event = RecordedEvent {
+            timestamp_micros,
+            event: Some(event),
+        };
+        let encoded_len = recorded_event.encoded_len();
+        let mut buffer = Vec::with_capacity(prost::length_delimiter_len(encoded_len) + encoded_len);
+        recorded_event.encode_length_delimited(&mut buffer).unwrap();
+        self.writer.write_all(&buffer)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
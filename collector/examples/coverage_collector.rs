@@ -13,10 +13,27 @@
 // limitations under the License.
 
 use async_trait::async_trait;
-use common::observer_proto::{structure_graph::Node as GraphNode, StructureGraph};
-use std::{collections::HashSet, sync::Mutex};
+use collector_service::{BitCounter, RemoveReason};
+use common::observer_proto::{
+    structure_graph::Node as GraphNode, Feature, FuzzerMetadata, StructureGraph,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    fs::File,
+    io::BufReader,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
 use tonic::transport::Server;
 
+// Fuzzers sample less often the longer they go without new coverage, and the
+// more fuzzers share the collector.
+const BASE_SAMPLE_INTERVAL: u64 = 16384;
+const PLATEAU_STEP: Duration = Duration::from_secs(60);
+const MAX_PLATEAU_STEPS: u32 = 4;
+const FUZZERS_PER_BASE_INTERVAL: u64 = 16;
+
 struct Node {
     graph_node: GraphNode,
     function_index: usize,
@@ -35,6 +52,8 @@ struct ObserverInner {
     frontiers: HashSet<usize>,
     covered_nodes: usize,
     covered_functions: usize,
+    // When each fuzzer last found new coverage.
+    last_coverage_times: HashMap<u64, Instant>,
 }
 
 struct Observer {
@@ -43,6 +62,7 @@ struct Observer {
 
 impl ObserverInner {
     fn create_fuzzer(&mut self, fuzzer_id: u64, struct_graph: &StructureGraph) {
+        self.last_coverage_times.insert(fuzzer_id, Instant::now());
         if fuzzer_id != 0 {
             return;
         }
@@ -72,7 +92,7 @@ impl ObserverInner {
         self.nodes = nodes;
     }
 
-    fn update_features(&mut self, bit_counters: &[(usize, u8)]) {
+    fn update_features(&mut self, fuzzer_id: u64, bit_counters: &[(usize, BitCounter)]) {
         let mut new_update = false;
         let mut new_function_names = Vec::new();
         for &(node_index, _) in bit_counters {
@@ -105,6 +125,7 @@ impl ObserverInner {
             new_update = true;
         }
         if new_update {
+            self.last_coverage_times.insert(fuzzer_id, Instant::now());
             println!(
                 "Covered Nodes: {} ({}) / Total Nodes: {} ({}) / Frontiers: {}",
                 self.covered_nodes,
@@ -118,26 +139,57 @@ impl ObserverInner {
             println!("New Functions: {:?}", new_function_names);
         }
     }
+
+    fn sample_interval(&self, fuzzer_id: u64) -> Option<u64> {
+        let plateau = self.last_coverage_times.get(&fuzzer_id)?.elapsed();
+        let plateau_steps =
+            ((plateau.as_secs() / PLATEAU_STEP.as_secs()) as u32).min(MAX_PLATEAU_STEPS);
+        let load = (self.last_coverage_times.len() as u64 / FUZZERS_PER_BASE_INTERVAL).max(1);
+        Some((BASE_SAMPLE_INTERVAL << plateau_steps) * load)
+    }
 }
 
 #[async_trait]
 impl collector_service::Observer for Observer {
-    async fn create_fuzzer(&self, fuzzer_id: u64, struct_graph: &StructureGraph) {
+    async fn create_fuzzer(
+        &self,
+        fuzzer_id: u64,
+        _metadata: &FuzzerMetadata,
+        struct_graph: &StructureGraph,
+    ) {
         self.inner
             .lock()
             .unwrap()
             .create_fuzzer(fuzzer_id, struct_graph);
     }
 
-    async fn update_features(&self, _fuzzer_id: u64, bit_counters: &[(usize, u8)]) {
-        self.inner.lock().unwrap().update_features(bit_counters);
+    async fn update_features(
+        &self,
+        fuzzer_id: u64,
+        bit_counters: &[(usize, BitCounter)],
+        _features: &[Feature],
+    ) {
+        self.inner
+            .lock()
+            .unwrap()
+            .update_features(fuzzer_id, bit_counters);
+    }
+
+    async fn sample_interval(&self, fuzzer_id: u64) -> Option<u64> {
+        self.inner.lock().unwrap().sample_interval(fuzzer_id)
+    }
+
+    async fn remove_fuzzer(&self, fuzzer_id: u64, _reason: RemoveReason) {
+        self.inner
+            .lock()
+            .unwrap()
+            .last_coverage_times
+            .remove(&fuzzer_id);
     }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let addr = "[::1]:2501".parse().unwrap();
-    println!("Collector Service listening on {}.", addr);
     let observer_ptr = Box::new(Observer {
         inner: Mutex::new(ObserverInner {
             nodes: Vec::new(),
@@ -145,10 +197,23 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             frontiers: HashSet::new(),
             covered_nodes: 0,
             covered_functions: 0,
+            last_coverage_times: HashMap::new(),
         }),
     });
+    // Replays a recording made without a collector instead of listening.
+    if let Some(recording_path) = env::args().nth(1) {
+        let recording = BufReader::new(File::open(recording_path)?);
+        collector_service::replay_recording(recording, observer_ptr).await?;
+        return Ok(());
+    }
+
+    let addr = "[::1]:2501".parse().unwrap();
+    println!("Collector Service listening on {}.", addr);
     Server::builder()
-        .add_service(collector_service::create_service(observer_ptr))
+        .add_service(collector_service::create_service(
+            observer_ptr,
+            collector_service::Controller::new(),
+        ))
         .serve(addr)
         .await?;
     Ok(())
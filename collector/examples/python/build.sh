@@ -18,6 +18,10 @@ python -m grpc_tools.protoc -I../../../proto \
     --python_out=. \
     --grpc_python_out=. \
     ../../../proto/observer_service.proto \
+    ../../../proto/crash.proto \
+    ../../../proto/feature.proto \
+    ../../../proto/fuzzer_metadata.proto \
+    ../../../proto/fuzzer_stats.proto \
     ../../../proto/structure_graph.proto
 
 (cd ../../ && cargo build --release --bin observer_proxy)
@@ -19,6 +19,7 @@ from concurrent import futures
 
 import grpc
 
+import feature_pb2
 import observer_service_pb2
 import observer_service_pb2_grpc
 
@@ -28,6 +29,7 @@ class CoverageObserverService(observer_service_pb2_grpc.ObserverServiceServicer)
         self.struct_graph = None
         self.node_map = None
         self.coverage = 0
+        self.value_profile_features = set()
 
     def CreateFuzzer(self, req, ctx):
         if req.fuzzer_id == 0:
@@ -43,12 +45,34 @@ class CoverageObserverService(observer_service_pb2_grpc.ObserverServiceServicer)
                 self.node_map[bit_counter.node_index] = True
                 self.coverage += 1
                 has_update = True
+        for feature in req.features:
+            if (feature.kind == feature_pb2.VALUE_PROFILE and
+                    feature.index not in self.value_profile_features):
+                self.value_profile_features.add(feature.index)
+                has_update = True
 
         if has_update:
-            print(f'{self.coverage} / {len(self.struct_graph.nodes)}')
+            print(f'{self.coverage} / {len(self.struct_graph.nodes)}, '
+                  f'{len(self.value_profile_features)} value profile features')
 
         return observer_service_pb2.UpdateFeaturesResponse()
 
+    def UpdateEdges(self, req, ctx):
+        return observer_service_pb2.UpdateEdgesResponse()
+
+    def RemoveFuzzer(self, req, ctx):
+        return observer_service_pb2.RemoveFuzzerResponse()
+
+    def UpdateStats(self, req, ctx):
+        return observer_service_pb2.UpdateStatsResponse()
+
+    def ReportCrash(self, req, ctx):
+        print(f'Fuzzer {req.fuzzer_id} found a {req.crash.kind}:')
+        for frame in req.crash.stack_frames:
+            print(f'    {frame}')
+
+        return observer_service_pb2.ReportCrashResponse()
+
 
 def start_server():
     server = grpc.server(futures.ThreadPoolExecutor(max_workers=4))
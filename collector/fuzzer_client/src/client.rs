@@ -12,44 +12,144 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use common::collector_proto::collector_service_client::CollectorServiceClient;
-use std::{future::Future, mem::MaybeUninit};
-use tokio::runtime;
+use crate::{
+    sampler::Sampler,
+    session::{with_timeout, ServiceClient, Session, NO_FUZZER_ID},
+};
+use common::collector_proto::{
+    ControlCommand, ControlFlowGraph, CreateFuzzerRequest, UpdateFeaturesRequest,
+    UpdateStatsRequest,
+};
+use std::{
+    error::Error,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    runtime,
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+    time,
+};
+use tonic::transport::Endpoint;
+
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub struct Client {
-    server_url: String,
     runtime: runtime::Runtime,
-    client: MaybeUninit<CollectorServiceClient<tonic::transport::channel::Channel>>,
+    client: ServiceClient,
+    rpc_timeout: Duration,
+    fuzzer_id: Arc<AtomicU64>,
+    session_handle: Option<JoinHandle<()>>,
+    command_receiver: Option<mpsc::Receiver<ControlCommand>>,
+    seed_receiver: Option<mpsc::Receiver<Vec<u8>>>,
 }
 
 impl Client {
-    pub fn new(server_url: &str) -> Self {
-        let runtime = runtime::Builder::new_current_thread()
+    /// Fails if the server URL is invalid or the runtime can't be started.
+    pub fn new(server_url: &str, rpc_timeout: Duration) -> Result<Self, Box<dyn Error>> {
+        // A single worker keeps the session running in the background while
+        // the fuzzing thread only enqueues requests.
+        let runtime = runtime::Builder::new_multi_thread()
+            .worker_threads(1)
             .enable_all()
-            .build()
-            .unwrap();
-        Self {
-            server_url: server_url.to_owned(),
+            .build()?;
+        // Connects lazily, so an unavailable collector doesn't stop the fuzzer.
+        let channel = {
+            let _guard = runtime.enter();
+            Endpoint::from_shared(server_url.to_owned())?.connect_lazy()?
+        };
+        Ok(Self {
             runtime,
-            client: MaybeUninit::zeroed(),
-        }
-    }
-
-    pub fn connect(&mut self) {
-        let server_url = self.server_url.clone();
-        let client = self
-            .runtime
-            .block_on(async { CollectorServiceClient::connect(server_url).await })
-            .unwrap();
-        self.client = MaybeUninit::new(client);
+            client: ServiceClient::new(channel),
+            rpc_timeout,
+            fuzzer_id: Arc::new(AtomicU64::new(NO_FUZZER_ID)),
+            session_handle: None,
+            command_receiver: None,
+            seed_receiver: None,
+        })
     }
 
-    pub fn call<'a, T, F>(&'a mut self, f: T) -> F::Output
+    /// Returns None if the collector doesn't respond in time.
+    pub fn call<'a, T, F>(&'a mut self, f: T) -> Option<F::Output>
     where
         F: Future + 'a,
-        T: FnOnce(&'a mut CollectorServiceClient<tonic::transport::channel::Channel>) -> F,
+        T: FnOnce(&'a mut ServiceClient) -> F,
     {
+        let client = &mut self.client;
+        let rpc_timeout = self.rpc_timeout;
         self.runtime
-            .block_on(f(unsafe { &mut *self.client.as_mut_ptr() }))
+            .block_on(async move { time::timeout(rpc_timeout, f(client)).await })
+            .ok()
+    }
+
+    /// Returns the fuzzer ID if the fuzzer is currently registered.
+    pub fn fuzzer_id(&self) -> Option<u64> {
+        match self.fuzzer_id.load(Ordering::SeqCst) {
+            NO_FUZZER_ID => None,
+            fuzzer_id => Some(fuzzer_id),
+        }
+    }
+
+    /// Registers the fuzzer and keeps it registered in the background. Only
+    /// waits for the first attempt, so the fuzzer runs even if the collector
+    /// is unavailable or still analyzing the CFG. Returns the sender of the
+    /// feature updates, and whether the collector accepted the fuzzer. The
+    /// interval of `sampler` is set as directed by the collector.
+    pub fn start_session(
+        &mut self,
+        create_fuzzer_req: CreateFuzzerRequest,
+        cfg: ControlFlowGraph,
+        sampler: &'static Sampler,
+    ) -> (mpsc::Sender<UpdateFeaturesRequest>, bool) {
+        let (session, feature_sender, command_receiver, seed_receiver) = Session::new(
+            self.client.clone(),
+            create_fuzzer_req,
+            cfg,
+            self.fuzzer_id.clone(),
+            self.rpc_timeout,
+            sampler,
+        );
+        let (first_attempt_sender, first_attempt_receiver) = oneshot::channel();
+        self.session_handle = Some(self.runtime.spawn(session.run(first_attempt_sender)));
+        let accepted = self
+            .runtime
+            .block_on(first_attempt_receiver)
+            .unwrap_or(false);
+        self.command_receiver = Some(command_receiver);
+        self.seed_receiver = Some(seed_receiver);
+        (feature_sender, accepted)
+    }
+
+    /// Waits until the session ends after its feature sender is dropped, and
+    /// the pending updates are sent.
+    pub fn close_session(&mut self) {
+        if let Some(handle) = self.session_handle.take() {
+            let _ = self
+                .runtime
+                .block_on(async { time::timeout(SHUTDOWN_TIMEOUT, handle).await });
+        }
+    }
+
+    /// Sends the stats in the background, so the fuzzing thread doesn't wait.
+    pub fn send_stats(&self, req: UpdateStatsRequest) {
+        let mut client = self.client.clone();
+        let rpc_timeout = self.rpc_timeout;
+        self.runtime.spawn(async move {
+            // Stats are sent periodically, a lost update is soon replaced.
+            let _ = with_timeout(rpc_timeout, client.update_stats(req)).await;
+        });
+    }
+
+    pub fn poll_command(&mut self) -> Option<ControlCommand> {
+        self.command_receiver.as_mut()?.try_recv().ok()
+    }
+
+    pub fn pull_seed(&mut self) -> Option<Vec<u8>> {
+        self.seed_receiver.as_mut()?.try_recv().ok()
     }
 }
@@ -0,0 +1,247 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::feature_reporter::DropPolicy;
+use std::{env, fs, time::Duration};
+
+const CONFIG_PATH_ENV: &str = "FUZVISOR_CONFIG";
+// Each key can also be set by the environment variable named by the uppercase
+// key with this prefix, which overrides the config file.
+const ENV_PREFIX: &str = "FUZVISOR_";
+const KEYS: &[&str] = &[
+    "server_url",
+    "recording_path",
+    "sample_interval",
+    "flush_interval_ms",
+    "rpc_timeout_ms",
+    "feature_queue_capacity",
+    "feature_drop_policy",
+    "on_error",
+];
+
+/// What to do when the fuzzer can't be reported at startup, e.g. the collector
+/// is unavailable or the recording file can't be created.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnError {
+    /// Keep fuzzing, and keep trying to reach the collector.
+    Continue,
+    /// Stop the fuzzer.
+    Abort,
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub server_url: String,
+    /// Records the fuzzer to this file, suffixed with the process ID, instead
+    /// of sending it to a collector. Set to an empty path to record nothing.
+    pub recording_path: Option<String>,
+    /// On average, one of this many executions reports its full feature set
+    /// even without new coverage. 0 disables sampling.
+    pub sample_interval: u64,
+    /// How often pending feature updates are batched and sent.
+    pub flush_interval: Duration,
+    /// How long to wait for the collector to answer a call, including
+    /// connecting to it. The CFG upload isn't bounded, as large CFGs take
+    /// longer to send.
+    pub rpc_timeout: Duration,
+    pub feature_queue_capacity: usize,
+    pub feature_drop_policy: DropPolicy,
+    pub on_error: OnError,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            server_url: "http://[::1]:2501".to_owned(),
+            recording_path: None,
+            sample_interval: 16384,
+            flush_interval: Duration::from_millis(20),
+            rpc_timeout: Duration::from_secs(10),
+            feature_queue_capacity: 1024,
+            feature_drop_policy: DropPolicy::DropNewest,
+            on_error: OnError::Continue,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config file named by `FUZVISOR_CONFIG` if set, then applies
+    /// the environment variables. Invalid values are ignored with a warning.
+    ///
+    /// The config file has a `key = value` per line. Empty lines and lines
+    /// starting with `#` are skipped.
+    pub fn load() -> Self {
+        let content = env::var(CONFIG_PATH_ENV).ok().and_then(|path| {
+            fs::read_to_string(&path)
+                .map_err(|error| {
+                    eprintln!("fuzzer_client: Failed to read config {}: {}", path, error)
+                })
+                .ok()
+        });
+        Self::parse(content.as_deref(), |name| env::var(name).ok())
+    }
+
+    fn parse(content: Option<&str>, env_var: impl Fn(&str) -> Option<String>) -> Self {
+        let mut config = Config::default();
+        for line in content.unwrap_or_default().lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once('=') {
+                Some((key, value)) => config.set(key.trim(), value.trim()),
+                None => eprintln!("fuzzer_client: Ignoring config line {:?}.", line),
+            }
+        }
+        for key in KEYS {
+            if let Some(value) = env_var(&(ENV_PREFIX.to_owned() + &key.to_uppercase())) {
+                config.set(key, &value);
+            }
+        }
+        config
+    }
+
+    fn set(&mut self, key: &str, value: &str) {
+        if !self.try_set(key, value) {
+            eprintln!("fuzzer_client: Ignoring config {} = {:?}.", key, value);
+        }
+    }
+
+    // Returns false if the key is unknown or the value invalid, in which case
+    // the config is left unchanged.
+    fn try_set(&mut self, key: &str, value: &str) -> bool {
+        match key {
+            "server_url" => self.server_url = value.to_owned(),
+            // An empty path disables recording, e.g. to override the file.
+            "recording_path" if value.is_empty() => self.recording_path = None,
+            "recording_path" => self.recording_path = Some(value.to_owned()),
+            "sample_interval" => match value.parse() {
+                Ok(sample_interval) => self.sample_interval = sample_interval,
+                Err(_) => return false,
+            },
+            "flush_interval_ms" => return set_millis(&mut self.flush_interval, value),
+            "rpc_timeout_ms" => return set_millis(&mut self.rpc_timeout, value),
+            "feature_queue_capacity" => match value.parse() {
+                Ok(capacity) if capacity > 0 => self.feature_queue_capacity = capacity,
+                _ => return false,
+            },
+            "feature_drop_policy" => match value {
+                "newest" => self.feature_drop_policy = DropPolicy::DropNewest,
+                "oldest" => self.feature_drop_policy = DropPolicy::DropOldest,
+                _ => return false,
+            },
+            "on_error" => match value {
+                "continue" => self.on_error = OnError::Continue,
+                "abort" => self.on_error = OnError::Abort,
+                _ => return false,
+            },
+            _ => return false,
+        }
+        true
+    }
+}
+
+fn set_millis(field: &mut Duration, value: &str) -> bool {
+    match value.parse() {
+        Ok(millis) => {
+            *field = Duration::from_millis(millis);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn parse(content: Option<&str>, env_vars: &[(&str, &str)]) -> Config {
+        let env_vars: HashMap<String, String> = env_vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        Config::parse(content, |name| env_vars.get(name).cloned())
+    }
+
+    #[test]
+    fn reads_config_file() {
+        let config = parse(
+            Some(
+                "# Collector\n\
+                 server_url = http://collector:2501\n\
+                 \n\
+                 sample_interval=100\n\
+                 flush_interval_ms = 5\n\
+                 rpc_timeout_ms = 2000\n\
+                 feature_queue_capacity = 16\n\
+                 feature_drop_policy = oldest\n\
+                 on_error = abort\n\
+                 recording_path = /tmp/fuzzer.rec\n",
+            ),
+            &[],
+        );
+        assert_eq!(config.server_url, "http://collector:2501");
+        assert_eq!(config.sample_interval, 100);
+        assert_eq!(config.flush_interval, Duration::from_millis(5));
+        assert_eq!(config.rpc_timeout, Duration::from_secs(2));
+        assert_eq!(config.feature_queue_capacity, 16);
+        assert_eq!(config.feature_drop_policy, DropPolicy::DropOldest);
+        assert_eq!(config.on_error, OnError::Abort);
+        assert_eq!(config.recording_path.as_deref(), Some("/tmp/fuzzer.rec"));
+    }
+
+    #[test]
+    fn env_vars_override_config_file() {
+        let config = parse(
+            Some("sample_interval = 100\nrpc_timeout_ms = 2000\nrecording_path = /tmp/fuzzer.rec"),
+            &[
+                ("FUZVISOR_SAMPLE_INTERVAL", "0"),
+                ("FUZVISOR_RECORDING_PATH", ""),
+                ("FUZVISOR_ON_ERROR", "abort"),
+            ],
+        );
+        assert_eq!(config.sample_interval, 0);
+        assert_eq!(config.rpc_timeout, Duration::from_secs(2));
+        assert_eq!(config.recording_path, None);
+        assert_eq!(config.on_error, OnError::Abort);
+    }
+
+    #[test]
+    fn ignores_invalid_values() {
+        let config = parse(
+            Some(
+                "sample_interval = -1\n\
+                 feature_queue_capacity = 0\n\
+                 feature_drop_policy = random\n\
+                 unknown_key = 1\n\
+                 not a setting\n\
+                 recording_path =\n",
+            ),
+            &[("FUZVISOR_RPC_TIMEOUT_MS", "1s"), ("FUZVISOR_ON_ERROR", "")],
+        );
+        let default_config = Config::default();
+        assert_eq!(config.sample_interval, default_config.sample_interval);
+        assert_eq!(
+            config.feature_queue_capacity,
+            default_config.feature_queue_capacity
+        );
+        assert_eq!(
+            config.feature_drop_policy,
+            default_config.feature_drop_policy
+        );
+        assert_eq!(config.rpc_timeout, default_config.rpc_timeout);
+        assert_eq!(config.on_error, default_config.on_error);
+        assert_eq!(config.recording_path, None);
+    }
+}
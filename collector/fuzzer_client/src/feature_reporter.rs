@@ -0,0 +1,236 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use common::collector_proto::UpdateFeaturesRequest;
+use crossbeam_queue::ArrayQueue;
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+const MAX_BATCH_SIZE: usize = 256;
+// How long closing waits for a slow collector to take the pending updates.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Which update to give up when the queue is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DropPolicy {
+    DropNewest,
+    DropOldest,
+}
+
+/// Hands feature updates from the fuzzing thread to a background thread,
+/// which sends them to the collector. Pushing never blocks, so the fuzzing
+/// throughput doesn't depend on the collector.
+pub struct FeatureReporter {
+    queue: ArrayQueue<UpdateFeaturesRequest>,
+    drop_policy: DropPolicy,
+    flush_interval: Duration,
+    closed: AtomicBool,
+    sender_thread: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+impl FeatureReporter {
+    pub fn new(capacity: usize, drop_policy: DropPolicy, flush_interval: Duration) -> Self {
+        Self {
+            queue: ArrayQueue::new(capacity),
+            drop_policy,
+            flush_interval,
+            closed: AtomicBool::new(false),
+            sender_thread: Mutex::new(None),
+        }
+    }
+
+    /// Enqueues the update, dropping one according to the drop policy if the
+    /// queue is full.
+    pub fn push(&self, req: UpdateFeaturesRequest) {
+        match self.drop_policy {
+            DropPolicy::DropNewest => {
+                let _ = self.queue.push(req);
+            }
+            DropPolicy::DropOldest => {
+                self.queue.force_push(req);
+            }
+        }
+    }
+
+    /// Starts the background thread forwarding the updates to `sender`.
+    pub fn start(&'static self, sender: mpsc::Sender<UpdateFeaturesRequest>) {
+        let handle = thread::spawn(move || self.run(sender));
+        *self.sender_thread.lock().unwrap() = Some(handle);
+    }
+
+    /// Stops the background thread after it sends the pending updates. The
+    /// updates which can't be sent within `CLOSE_TIMEOUT` are dropped.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.sender_thread.lock().unwrap().take() {
+            handle.join().unwrap();
+        }
+    }
+
+    // Never blocks on the sender, so a stalled collector can't keep the fuzzer
+    // from exiting. Meanwhile, the queue applies the drop policy.
+    fn run(&self, sender: mpsc::Sender<UpdateFeaturesRequest>) {
+        // Updates which didn't fit in the channel, sent first on the next try.
+        let mut pending = VecDeque::new();
+        let mut close_deadline = None;
+        loop {
+            // Check before draining, so no update pushed before closing is left.
+            let closed = self.closed.load(Ordering::SeqCst);
+            if closed && close_deadline.is_none() {
+                close_deadline = Some(Instant::now() + CLOSE_TIMEOUT);
+            }
+            loop {
+                if pending.is_empty() {
+                    pending.extend(self.next_batch());
+                }
+                let req = match pending.pop_front() {
+                    Some(req) => req,
+                    None => break,
+                };
+                match sender.try_send(req) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(req)) => {
+                        pending.push_front(req);
+                        break;
+                    }
+                    // If the feature stream is gone, keep draining so the
+                    // queue doesn't stay full.
+                    Err(TrySendError::Closed(_)) => {}
+                }
+            }
+            if let Some(close_deadline) = close_deadline {
+                if pending.is_empty() || Instant::now() >= close_deadline {
+                    return;
+                }
+            }
+            thread::park_timeout(self.flush_interval);
+        }
+    }
+
+    /// Pops up to `MAX_BATCH_SIZE` updates. Each update holds the features of
+    /// a single execution, which the collector infers the coverage from, so
+    /// they are never merged. Only a sampled update without a seed which
+    /// repeats the previous sampled one is dropped, as it adds nothing.
+    fn next_batch(&self) -> Vec<UpdateFeaturesRequest> {
+        let mut batch: Vec<UpdateFeaturesRequest> = Vec::new();
+        let mut last_sampled: Option<usize> = None;
+        for _ in 0..MAX_BATCH_SIZE {
+            let req = match self.queue.pop() {
+                Some(req) => req,
+                None => break,
+            };
+            if req.seed_sha1.is_empty() {
+                if let Some(last_sampled) = last_sampled {
+                    if batch[last_sampled].features == req.features {
+                        continue;
+                    }
+                }
+                last_sampled = Some(batch.len());
+            }
+            batch.push(req);
+        }
+        batch
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sampled_update(features: &[u32]) -> UpdateFeaturesRequest {
+        UpdateFeaturesRequest {
+            features: features.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    fn seed_update(features: &[u32]) -> UpdateFeaturesRequest {
+        UpdateFeaturesRequest {
+            features: features.to_vec(),
+            seed_sha1: vec![0; 20],
+            ..Default::default()
+        }
+    }
+
+    fn batch_features(feature_reporter: &FeatureReporter) -> Vec<Vec<u32>> {
+        feature_reporter
+            .next_batch()
+            .into_iter()
+            .map(|req| req.features)
+            .collect()
+    }
+
+    #[test]
+    fn drop_newest_keeps_queued_updates() {
+        let feature_reporter =
+            FeatureReporter::new(2, DropPolicy::DropNewest, Duration::from_millis(1));
+        for feature in 0..4 {
+            feature_reporter.push(seed_update(&[feature]));
+        }
+        assert_eq!(batch_features(&feature_reporter), [[0], [1]]);
+    }
+
+    #[test]
+    fn drop_oldest_keeps_latest_updates() {
+        let feature_reporter =
+            FeatureReporter::new(2, DropPolicy::DropOldest, Duration::from_millis(1));
+        for feature in 0..4 {
+            feature_reporter.push(seed_update(&[feature]));
+        }
+        assert_eq!(batch_features(&feature_reporter), [[2], [3]]);
+    }
+
+    #[test]
+    fn batch_keeps_updates_apart() {
+        let feature_reporter =
+            FeatureReporter::new(16, DropPolicy::DropNewest, Duration::from_millis(1));
+        feature_reporter.push(sampled_update(&[1, 2]));
+        // Repeats the previous sampled update.
+        feature_reporter.push(sampled_update(&[1, 2]));
+        feature_reporter.push(seed_update(&[1, 2]));
+        feature_reporter.push(sampled_update(&[1, 3]));
+        feature_reporter.push(sampled_update(&[1, 2]));
+        let batch = feature_reporter.next_batch();
+        let features: Vec<_> = batch.iter().map(|req| req.features.clone()).collect();
+        assert_eq!(features, [[1, 2], [1, 2], [1, 3], [1, 2]]);
+        assert!(!batch[1].seed_sha1.is_empty());
+    }
+
+    #[test]
+    fn close_sends_pending_updates() {
+        let feature_reporter: &'static FeatureReporter = Box::leak(Box::new(FeatureReporter::new(
+            16,
+            DropPolicy::DropNewest,
+            Duration::from_millis(1),
+        )));
+        let (sender, mut receiver) = mpsc::channel(16);
+        feature_reporter.start(sender);
+        for feature in 0..3 {
+            feature_reporter.push(seed_update(&[feature]));
+        }
+        feature_reporter.close();
+        for feature in 0..3 {
+            assert_eq!(receiver.try_recv().unwrap().features, [feature]);
+        }
+        assert!(receiver.try_recv().is_err());
+    }
+}
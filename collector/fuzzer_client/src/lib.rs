@@ -13,25 +13,32 @@
 // limitations under the License.
 
 mod client;
+mod config;
+mod feature_reporter;
+mod metadata;
+mod recorder;
+mod sampler;
+mod session;
 use client::Client;
 use common::{
     collector_proto::{
+        control_command::Command,
         control_flow_graph::{BasicBlock, Function},
-        ControlFlowGraph, CreateFuzzerRequest, UpdateFeaturesRequest,
+        ControlFlowGraph, CreateFuzzerRequest, DeleteFuzzerRequest, FeatureLayout,
+        ReportCrashRequest, UpdateFeaturesRequest, UpdateStatsRequest,
     },
+    hash_cfg,
+    observer_proto::{Crash, FuzzerStats},
     NO_SANCOV_INDEX,
 };
+use config::{Config, OnError};
+use feature_reporter::FeatureReporter;
 use lazy_static::lazy_static;
+use metadata::collect_fuzzer_metadata;
 use prost::Message;
-use std::{
-    collections::HashMap,
-    env,
-    sync::atomic::{AtomicU64, Ordering},
-    sync::Mutex,
-};
-
-const SERVER_URL_ENV: &str = "FUZVISOR_SERVER_URL";
-const DEFAULT_SERVER_URL: &str = "http://[::1]:2501";
+use recorder::Recorder;
+use sampler::Sampler;
+use std::{collections::HashMap, ffi::CStr, os::raw::c_char, sync::Mutex};
 
 #[repr(C)]
 struct fuzzer_client_param_cfg_payload_data {
@@ -55,23 +62,99 @@ struct fuzzer_client_param_module {
 pub struct fuzzer_client_param {
     modules: *const fuzzer_client_param_module,
     modules_size: usize,
+    job_index: i32,
+    counters_size: u64,
+    value_profile_size: u64,
+}
+
+const SHA1_SIZE: usize = 20;
+
+#[repr(C)]
+pub enum fuzzer_client_command_kind {
+    None = 0,
+    Stop = 1,
+    SetSeedPriorities = 2,
+}
+
+#[repr(C)]
+pub struct fuzzer_client_seed_priority {
+    sha1: [u8; SHA1_SIZE],
+    energy: f64,
+}
+
+#[repr(C)]
+pub struct fuzzer_client_command {
+    kind: fuzzer_client_command_kind,
+    seed_priorities: *const fuzzer_client_seed_priority,
+    seed_priorities_size: usize,
+}
+
+#[repr(C)]
+pub struct fuzzer_client_crash {
+    kind: *const c_char,
+    input: *const u8,
+    input_size: usize,
+    report: *const c_char,
+    stack_frames: *const *const c_char,
+    stack_frames_size: usize,
+}
+
+#[repr(C)]
+pub struct fuzzer_client_stats {
+    execs_per_sec: u64,
+    total_execs: u64,
+    corpus_size: u64,
+    corpus_bytes: u64,
+    peak_rss_mb: u64,
+    slowest_unit_time_sec: u64,
+    timeouts: u64,
+    ooms: u64,
+}
+
+#[repr(C)]
+pub struct fuzzer_client_seed {
+    data: *const u8,
+    size: usize,
 }
 
-static FUZZER_ID: AtomicU64 = AtomicU64::new(std::u64::MAX);
 lazy_static! {
-    static ref SERVICE_CLIENT: Mutex<Client> = Mutex::new(Client::new(
-        &env::var(SERVER_URL_ENV.to_owned()).unwrap_or(DEFAULT_SERVER_URL.to_owned())
-    ));
+    static ref CONFIG: Config = Config::load();
+    // None if the client can't be created, e.g. the server URL is invalid, in
+    // which case the fuzzer runs without a collector.
+    static ref SERVICE_CLIENT: Mutex<Option<Client>> = Mutex::new(
+        Client::new(&CONFIG.server_url, CONFIG.rpc_timeout)
+            .map_err(|error| eprintln!("fuzzer_client: Failed to create the client: {}", error))
+            .ok()
+    );
+    // Kept apart from the client, so reporting features never waits on it.
+    static ref FEATURE_REPORTER: FeatureReporter = FeatureReporter::new(
+        CONFIG.feature_queue_capacity,
+        CONFIG.feature_drop_policy,
+        CONFIG.flush_interval,
+    );
+    static ref SAMPLER: Sampler = Sampler::new(CONFIG.sample_interval);
+    static ref RECORDER: Mutex<Option<Recorder>> = Mutex::new(None);
+    // Backs the array handed out by the last polled command.
+    static ref POLLED_SEED_PRIORITIES: Mutex<Vec<fuzzer_client_seed_priority>> =
+        Mutex::new(Vec::new());
+    // Backs the data handed out by the last pulled seed.
+    static ref PULLED_SEED: Mutex<Vec<u8>> = Mutex::new(Vec::new());
 }
 
+/// Registers the fuzzer to the collector. If the collector is unavailable,
+/// the fuzzer keeps running and registers once it's back, unless `on_error` is
+/// set to abort. If the client can't be created, e.g. the server URL is
+/// invalid, it runs without a collector. If `recording_path` is set, the fuzzer
+/// is recorded to a file named after it instead.
+///
+/// # Safety
+///
+/// `param_ptr` must point to a valid `fuzzer_client_param` whose buffers stay
+/// alive for the duration of the call.
 #[no_mangle]
-pub extern "C" fn fuzzer_client_init(param_ptr: *const fuzzer_client_param) {
-    initialize_service_client();
-
-    let modules = unsafe {
-        let param = &*param_ptr;
-        std::slice::from_raw_parts(param.modules, param.modules_size)
-    };
+pub unsafe extern "C" fn fuzzer_client_init(param_ptr: *const fuzzer_client_param) {
+    let param = unsafe { &*param_ptr };
+    let modules = unsafe { std::slice::from_raw_parts(param.modules, param.modules_size) };
 
     let cfgs: Vec<ControlFlowGraph> = modules
         .iter()
@@ -83,43 +166,273 @@ pub extern "C" fn fuzzer_client_init(param_ptr: *const fuzzer_client_param) {
             let cfg_payload =
                 std::slice::from_raw_parts(module.cfg_payload.buffer, module.cfg_payload.size);
             let mut cfg = ControlFlowGraph::decode(cfg_payload).unwrap();
-            remap_sancov_index(&mut cfg, &remap_starts, &remap_offsets);
+            remap_sancov_index(&mut cfg, remap_starts, remap_offsets);
             cfg
         })
         .collect();
     let concat_cfg = concat_control_flow_graph(cfgs);
+    let metadata = collect_fuzzer_metadata(param.job_index);
 
-    let id = SERVICE_CLIENT
-        .lock()
-        .unwrap()
-        .call(|client| {
-            client.create_fuzzer(CreateFuzzerRequest {
-                cfg: Some(concat_cfg),
-            })
-        })
-        .unwrap()
-        .into_inner()
-        .id;
-    FUZZER_ID.store(id, Ordering::SeqCst);
+    let create_fuzzer_req = CreateFuzzerRequest {
+        cfg: None,
+        metadata: Some(metadata),
+        cfg_hash: hash_cfg(&concat_cfg),
+        feature_layout: Some(FeatureLayout {
+            counters_size: param.counters_size,
+            value_profile_size: param.value_profile_size,
+        }),
+    };
+
+    if let Some(recording_path) = CONFIG.recording_path.as_ref() {
+        match Recorder::create(recording_path, create_fuzzer_req, concat_cfg) {
+            Ok((recorder, feature_sender)) => {
+                FEATURE_REPORTER.start(feature_sender);
+                *RECORDER.lock().unwrap() = Some(recorder);
+            }
+            Err(error) if CONFIG.on_error == OnError::Abort => {
+                panic!("Failed to create the recording file: {}", error)
+            }
+            // Without a recorder, the updates are dropped once the queue is full.
+            Err(_) => {}
+        }
+        return;
+    }
+    let mut service_client = SERVICE_CLIENT.lock().unwrap();
+    let service_client = match service_client.as_mut() {
+        Some(service_client) => service_client,
+        None if CONFIG.on_error == OnError::Abort => {
+            panic!("Failed to create the collector client.")
+        }
+        // Without a collector, the updates are dropped once the queue is full.
+        None => return,
+    };
+    let (feature_sender, accepted) =
+        service_client.start_session(create_fuzzer_req, concat_cfg, &SAMPLER);
+    FEATURE_REPORTER.start(feature_sender);
+    if !accepted && CONFIG.on_error == OnError::Abort {
+        panic!("Failed to register the fuzzer to the collector.");
+    }
 }
 
+/// Flushes the pending feature updates and deletes the fuzzer from the
+/// collector, or finishes the recording.
 #[no_mangle]
-pub extern "C" fn fuzzer_client_update_features(features_ptr: *const u32, features_size: usize) {
+pub extern "C" fn fuzzer_client_fini() {
+    FEATURE_REPORTER.close();
+    if let Some(recorder) = RECORDER.lock().unwrap().take() {
+        recorder.close();
+        return;
+    }
+    let mut service_client = SERVICE_CLIENT.lock().unwrap();
+    let service_client = match service_client.as_mut() {
+        Some(service_client) => service_client,
+        None => return,
+    };
+    service_client.close_session();
+    let id = match service_client.fuzzer_id() {
+        Some(id) => id,
+        None => return,
+    };
+    // The collector may have already expired the fuzzer.
+    let _ = service_client.call(|client| client.delete_fuzzer(DeleteFuzzerRequest { id }));
+}
+
+/// Queues the features to be sent in the background. If the queue is full, an
+/// update is dropped according to the drop policy.
+///
+/// # Safety
+///
+/// `features_ptr` must point to `features_size` readable `u32` values.
+/// `seed_sha1_ptr` must be null or point to a SHA1 digest. `seed_ptr` must be
+/// null or point to `seed_size` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn fuzzer_client_update_features(
+    features_ptr: *const u32,
+    features_size: usize,
+    seed_sha1_ptr: *const u8,
+    seed_ptr: *const u8,
+    seed_size: usize,
+) {
     let features = unsafe { std::slice::from_raw_parts(features_ptr, features_size).to_vec() };
-    SERVICE_CLIENT
+    let seed_sha1 = if seed_sha1_ptr.is_null() {
+        Vec::new()
+    } else {
+        unsafe { std::slice::from_raw_parts(seed_sha1_ptr, SHA1_SIZE).to_vec() }
+    };
+    let seed = if seed_ptr.is_null() {
+        Vec::new()
+    } else {
+        unsafe { std::slice::from_raw_parts(seed_ptr, seed_size).to_vec() }
+    };
+    // The ID is filled in when sending, since re-registering changes it.
+    FEATURE_REPORTER.push(UpdateFeaturesRequest {
+        id: 0,
+        features,
+        seed_sha1,
+        seed,
+        ..Default::default()
+    });
+}
+
+/// # Safety
+///
+/// `stats_ptr` must point to a valid `fuzzer_client_stats`.
+#[no_mangle]
+pub unsafe extern "C" fn fuzzer_client_update_stats(stats_ptr: *const fuzzer_client_stats) {
+    let stats = unsafe { &*stats_ptr };
+    let service_client = SERVICE_CLIENT.lock().unwrap();
+    let service_client = match service_client.as_ref() {
+        Some(service_client) => service_client,
+        None => return,
+    };
+    let id = match service_client.fuzzer_id() {
+        Some(id) => id,
+        // Stats are sent periodically, later ones go through once registered.
+        None => return,
+    };
+    service_client.send_stats(UpdateStatsRequest {
+        id,
+        stats: Some(FuzzerStats {
+            execs_per_sec: stats.execs_per_sec,
+            total_execs: stats.total_execs,
+            corpus_size: stats.corpus_size,
+            corpus_bytes: stats.corpus_bytes,
+            peak_rss_mb: stats.peak_rss_mb,
+            slowest_unit_time_sec: stats.slowest_unit_time_sec,
+            timeouts: stats.timeouts,
+            ooms: stats.ooms,
+        }),
+    });
+}
+
+/// Tells whether the current execution reports its full feature set even
+/// without new coverage, according to `sample_interval`.
+#[no_mangle]
+pub extern "C" fn fuzzer_client_should_sample() -> bool {
+    SAMPLER.should_sample()
+}
+
+/// Pops the next pending command pushed by the collector. Returns false if
+/// there is none. Arrays referenced by the command stay valid until the next
+/// poll.
+///
+/// # Safety
+///
+/// `command_ptr` must point to a writable `fuzzer_client_command`.
+#[no_mangle]
+pub unsafe extern "C" fn fuzzer_client_poll_command(
+    command_ptr: *mut fuzzer_client_command,
+) -> bool {
+    let control_command = match SERVICE_CLIENT
         .lock()
         .unwrap()
-        .call(|client| {
-            client.update_features(UpdateFeaturesRequest {
-                id: FUZZER_ID.load(Ordering::SeqCst),
-                features,
-            })
+        .as_mut()
+        .and_then(Client::poll_command)
+    {
+        Some(control_command) => control_command,
+        None => return false,
+    };
+    let mut polled_seed_priorities = POLLED_SEED_PRIORITIES.lock().unwrap();
+    polled_seed_priorities.clear();
+    let command = unsafe { &mut *command_ptr };
+    command.kind = match control_command.command {
+        Some(Command::Stop(_)) => fuzzer_client_command_kind::Stop,
+        Some(Command::SetSeedPriorities(set_seed_priorities)) => {
+            for priority in set_seed_priorities.priorities {
+                if priority.sha1.len() != SHA1_SIZE {
+                    continue;
+                }
+                let mut sha1 = [0; SHA1_SIZE];
+                sha1.copy_from_slice(&priority.sha1);
+                polled_seed_priorities.push(fuzzer_client_seed_priority {
+                    sha1,
+                    // Clamped by the corpus, which relies on it.
+                    energy: priority.energy,
+                });
+            }
+            fuzzer_client_command_kind::SetSeedPriorities
+        }
+        // Seeds are handed out by fuzzer_client_pull_seed instead.
+        Some(Command::AddSeeds(_)) | None => fuzzer_client_command_kind::None,
+    };
+    command.seed_priorities = polled_seed_priorities.as_ptr();
+    command.seed_priorities_size = polled_seed_priorities.len();
+    true
+}
+
+/// Reports a crash to the collector and waits for it to be received, since the
+/// process usually exits right after.
+///
+/// # Safety
+///
+/// `crash_ptr` must point to a valid `fuzzer_client_crash`. `report` may be
+/// null.
+#[no_mangle]
+pub unsafe extern "C" fn fuzzer_client_report_crash(crash_ptr: *const fuzzer_client_crash) {
+    let mut service_client = SERVICE_CLIENT.lock().unwrap();
+    let service_client = match service_client.as_mut() {
+        Some(service_client) => service_client,
+        None => return,
+    };
+    let id = match service_client.fuzzer_id() {
+        Some(id) => id,
+        // The fuzzer isn't registered, e.g. the collector is unavailable.
+        None => return,
+    };
+    let crash = unsafe {
+        let crash = &*crash_ptr;
+        Crash {
+            kind: c_str_to_string(crash.kind),
+            input: std::slice::from_raw_parts(crash.input, crash.input_size).to_vec(),
+            report: c_str_to_string(crash.report),
+            stack_frames: std::slice::from_raw_parts(crash.stack_frames, crash.stack_frames_size)
+                .iter()
+                .map(|frame| c_str_to_string(*frame))
+                .collect(),
+            stack_hash: Vec::new(),
+        }
+    };
+    // The crash may be reported while the fuzzer is shutting down.
+    let _ = service_client.call(|client| {
+        client.report_crash(ReportCrashRequest {
+            id,
+            crash: Some(crash),
         })
-        .unwrap();
+    });
+}
+
+unsafe fn c_str_to_string(ptr: *const c_char) -> String {
+    if ptr.is_null() {
+        return String::new();
+    }
+    unsafe { CStr::from_ptr(ptr) }
+        .to_string_lossy()
+        .into_owned()
 }
 
-fn initialize_service_client() {
-    SERVICE_CLIENT.lock().unwrap().connect();
+/// Pops the next seed found by another fuzzer of the same target. Returns false
+/// if there is none. The seed data stays valid until the next pull.
+///
+/// # Safety
+///
+/// `seed_ptr` must point to a writable `fuzzer_client_seed`.
+#[no_mangle]
+pub unsafe extern "C" fn fuzzer_client_pull_seed(seed_ptr: *mut fuzzer_client_seed) -> bool {
+    let seed_data = match SERVICE_CLIENT
+        .lock()
+        .unwrap()
+        .as_mut()
+        .and_then(Client::pull_seed)
+    {
+        Some(seed_data) => seed_data,
+        None => return false,
+    };
+    let mut pulled_seed = PULLED_SEED.lock().unwrap();
+    *pulled_seed = seed_data;
+    let seed = unsafe { &mut *seed_ptr };
+    seed.data = pulled_seed.as_ptr();
+    seed.size = pulled_seed.len();
+    true
 }
 
 fn remap_sancov_index(cfg: &mut ControlFlowGraph, remap_starts: &[u64], remap_offsets: &[u64]) {
@@ -158,7 +471,7 @@ fn concat_control_flow_graph(cfgs: Vec<ControlFlowGraph>) -> ControlFlowGraph {
                     successors: basic_block
                         .successors
                         .iter()
-                        .map(|successor| block_id_mapper(successor))
+                        .map(&mut block_id_mapper)
                         .collect(),
                     sancov_index: basic_block.sancov_index,
                 });
@@ -0,0 +1,74 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use common::observer_proto::FuzzerMetadata;
+use std::{collections::HashMap, env, ffi::CStr, os::raw::c_char};
+
+// Comma-separated `key=value` pairs attached to the fuzzer, e.g.
+// `FUZVISOR_LABELS=target=png,bot=worker-3`.
+const LABELS_ENV: &str = "FUZVISOR_LABELS";
+const HOSTNAME_MAX_SIZE: usize = 256;
+
+pub fn collect_fuzzer_metadata(job_index: i32) -> FuzzerMetadata {
+    // env::args panics on arguments which aren't valid UTF-8, e.g. file names.
+    let argv: Vec<String> = env::args_os()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect();
+    FuzzerMetadata {
+        hostname: hostname(),
+        pid: std::process::id(),
+        libfuzzer_flags: parse_libfuzzer_flags(&argv),
+        argv,
+        job_index,
+        labels: env::var(LABELS_ENV)
+            .map(|labels| parse_key_values(labels.split(',')))
+            .unwrap_or_default(),
+    }
+}
+
+fn hostname() -> String {
+    let mut buffer = [0 as c_char; HOSTNAME_MAX_SIZE];
+    let ret = unsafe { libc::gethostname(buffer.as_mut_ptr(), buffer.len() - 1) };
+    if ret != 0 {
+        return String::new();
+    }
+    unsafe { CStr::from_ptr(buffer.as_ptr()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+// libFuzzer flags are passed as `-name=value`, everything else is an input
+// path.
+fn parse_libfuzzer_flags(argv: &[String]) -> HashMap<String, String> {
+    parse_key_values(
+        argv.iter()
+            .skip(1)
+            .filter(|arg| arg.starts_with('-') && !arg.starts_with("--"))
+            .map(|arg| &arg[1..]),
+    )
+}
+
+fn parse_key_values<'a>(pairs: impl Iterator<Item = &'a str>) -> HashMap<String, String> {
+    pairs
+        .filter_map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) if !key.is_empty() => {
+                    Some((key.to_owned(), value.to_owned()))
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,78 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use common::{
+    collector_proto::{
+        recorded_event::Event, ControlFlowGraph, CreateFuzzerRequest, UpdateFeaturesRequest,
+    },
+    RecordingWriter,
+};
+use std::{fs::OpenOptions, io, io::BufWriter, process, thread};
+use tokio::sync::mpsc;
+
+const FEATURE_CHANNEL_CAPACITY: usize = 1024;
+
+/// Records the fuzzer to a file instead of sending it to a collector, see
+/// `recording.proto` for the format.
+pub struct Recorder {
+    writer_thread: thread::JoinHandle<()>,
+}
+
+impl Recorder {
+    /// Creates the file and records the fuzzer registration with the full CFG.
+    /// The path is suffixed with the process ID, so the processes started by
+    /// `-jobs` or `-fork` each get a file of their own, and an existing file is
+    /// never overwritten. Returns the sender of the feature updates to record.
+    pub fn create(
+        path: &str,
+        mut create_fuzzer_req: CreateFuzzerRequest,
+        cfg: ControlFlowGraph,
+    ) -> io::Result<(Self, mpsc::Sender<UpdateFeaturesRequest>)> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(format!("{}.{}", path, process::id()))?;
+        let mut writer = RecordingWriter::new(BufWriter::new(file))?;
+        create_fuzzer_req.cfg = Some(cfg);
+        writer.write_event(Event::CreateFuzzer(create_fuzzer_req))?;
+        writer.flush()?;
+
+        let (sender, mut receiver) = mpsc::channel(FEATURE_CHANNEL_CAPACITY);
+        let writer_thread = thread::spawn(move || {
+            while let Some(req) = receiver.blocking_recv() {
+                let mut written = writer.write_event(Event::UpdateFeatures(req));
+                // Flush whenever idle, so a crash loses as few updates as possible.
+                while written.is_ok() {
+                    match receiver.try_recv() {
+                        Ok(req) => written = writer.write_event(Event::UpdateFeatures(req)),
+                        Err(_) => {
+                            written = writer.flush();
+                            break;
+                        }
+                    }
+                }
+                // Stop recording once writing fails, e.g. the disk is full.
+                if written.is_err() {
+                    break;
+                }
+            }
+        });
+        Ok((Self { writer_thread }, sender))
+    }
+
+    /// Waits until the updates are written, once their sender is dropped.
+    pub fn close(self) {
+        self.writer_thread.join().unwrap();
+    }
+}
@@ -0,0 +1,102 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    cell::Cell,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+thread_local! {
+    static RNG_STATE: Cell<u64> = const { Cell::new(0x9e37_79b9_7f4a_7c15) };
+}
+
+/// Picks the executions which report their full feature set without new
+/// coverage. Executions are picked at random rather than periodically, so the
+/// samples don't follow the patterns of the mutations.
+///
+/// The interval starts from the config, and then follows the collector.
+pub struct Sampler {
+    interval: AtomicU64,
+}
+
+impl Sampler {
+    /// Picks one of `interval` executions on average, none if it's 0.
+    pub fn new(interval: u64) -> Self {
+        Self {
+            interval: AtomicU64::new(interval),
+        }
+    }
+
+    pub fn set_interval(&self, interval: u64) {
+        self.interval.store(interval, Ordering::Relaxed);
+    }
+
+    pub fn should_sample(&self) -> bool {
+        let interval = self.interval.load(Ordering::Relaxed);
+        interval != 0 && next_random() % interval == 0
+    }
+}
+
+// Xorshift64, good enough to spread the samples and cheap enough to run on
+// every execution.
+fn next_random() -> u64 {
+    RNG_STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        state.set(x);
+        x
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count_samples(sampler: &Sampler, num_executions: usize) -> usize {
+        (0..num_executions)
+            .filter(|_| sampler.should_sample())
+            .count()
+    }
+
+    #[test]
+    fn zero_interval_never_samples() {
+        assert_eq!(count_samples(&Sampler::new(0), 1000), 0);
+    }
+
+    #[test]
+    fn unit_interval_always_samples() {
+        assert_eq!(count_samples(&Sampler::new(1), 1000), 1000);
+    }
+
+    #[test]
+    fn samples_once_per_interval_on_average() {
+        let num_samples = count_samples(&Sampler::new(16), 16 * 1000);
+        assert!(
+            (900..=1100).contains(&num_samples),
+            "{} samples",
+            num_samples
+        );
+    }
+
+    #[test]
+    fn follows_new_interval() {
+        let sampler = Sampler::new(1);
+        sampler.set_interval(0);
+        assert_eq!(count_samples(&sampler, 1000), 0);
+        sampler.set_interval(1);
+        assert_eq!(count_samples(&sampler, 1000), 1000);
+    }
+}
@@ -0,0 +1,743 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::sampler::Sampler;
+use common::{
+    collector_proto::{
+        collector_service_client::CollectorServiceClient, control_command::Command, ControlCommand,
+        ControlFlowGraph, ControlRequest, CreateFuzzerRequest, HeartbeatRequest,
+        UpdateFeaturesRequest, UploadCfgRequest,
+    },
+    FeatureEncoder,
+};
+use prost::Message;
+use std::{
+    collections::{HashSet, VecDeque},
+    future::Future,
+    mem,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time,
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{transport::Channel, Code, Status};
+
+pub type ServiceClient = CollectorServiceClient<Channel>;
+
+pub const NO_FUZZER_ID: u64 = u64::MAX;
+// Keeps each CFG chunk well below the gRPC message size limit.
+const CFG_CHUNK_SIZE: usize = 1 << 20;
+const FEATURE_STREAM_CAPACITY: usize = 1024;
+// Commands and seeds beyond this many pending are dropped, like on the
+// collector, so seeds redistributed faster than the fuzzer pulls them don't
+// pile up in its memory.
+const COMMAND_CHANNEL_CAPACITY: usize = 256;
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+// A collector which misses this many heartbeats in a row is treated as
+// disconnected, even if the connection is still open.
+const MAX_MISSED_HEARTBEATS: u32 = 3;
+const MIN_RECONNECT_DELAY: Duration = Duration::from_millis(500);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+// How often to retry the registration while the collector analyzes the CFG.
+const CFG_ANALYSIS_POLL_INTERVAL: Duration = Duration::from_secs(1);
+// Updates held while disconnected beyond this only keep their coverage for a
+// restarted collector.
+const MAX_PENDING_UPDATES: usize = 1024;
+// Bounds the memory of the updates kept to restore the coverage. Beyond this,
+// the coverage of later updates is lost if the collector restarts.
+const MAX_REPLAYED_FEATURES: usize = 1 << 22;
+
+/// Gives up on an RPC if the collector doesn't respond in time.
+pub async fn with_timeout<T>(
+    timeout: Duration,
+    rpc: impl Future<Output = Result<T, Status>>,
+) -> Result<T, Status> {
+    match time::timeout(timeout, rpc).await {
+        Ok(res) => res,
+        Err(_) => Err(Status::deadline_exceeded(
+            "Collector didn't respond in time.",
+        )),
+    }
+}
+
+enum SessionEnd {
+    Closed,
+    Disconnected,
+}
+
+/// Keeps the fuzzer registered to the collector. Whenever the connection is
+/// lost, it reconnects with exponential backoff and resumes the registration,
+/// or registers the fuzzer again if the collector no longer knows it.
+pub struct Session {
+    client: ServiceClient,
+    create_fuzzer_req: CreateFuzzerRequest,
+    cfg: ControlFlowGraph,
+    fuzzer_id: Arc<AtomicU64>,
+    // The fuzzer ID and the collector instance of the last registration.
+    registration: Option<(u64, u64)>,
+    rpc_timeout: Duration,
+    // Follows the sampling interval directed by the collector.
+    sampler: &'static Sampler,
+    feature_receiver: mpsc::Receiver<UpdateFeaturesRequest>,
+    command_sender: mpsc::Sender<ControlCommand>,
+    seed_sender: mpsc::Sender<Vec<u8>>,
+    // Every feature reported so far.
+    reported_features: HashSet<u32>,
+    // Feature sets of the updates which reported new features. A restarted
+    // collector has lost the coverage, so they are sent again after
+    // registering. They are kept per update, since the collector infers the
+    // coverage from the features hit by the same execution.
+    replayed_updates: Vec<Vec<u32>>,
+    num_replayed_features: usize,
+    // Updates with a seed or new features which couldn't be sent.
+    pending_updates: VecDeque<UpdateFeaturesRequest>,
+}
+
+impl Session {
+    /// Returns the session with the sender of the feature updates and the
+    /// receivers of the commands and seeds pushed by the collector. The session
+    /// ends once the feature sender is dropped.
+    pub fn new(
+        client: ServiceClient,
+        create_fuzzer_req: CreateFuzzerRequest,
+        cfg: ControlFlowGraph,
+        fuzzer_id: Arc<AtomicU64>,
+        rpc_timeout: Duration,
+        sampler: &'static Sampler,
+    ) -> (
+        Self,
+        mpsc::Sender<UpdateFeaturesRequest>,
+        mpsc::Receiver<ControlCommand>,
+        mpsc::Receiver<Vec<u8>>,
+    ) {
+        let (feature_sender, feature_receiver) = mpsc::channel(FEATURE_STREAM_CAPACITY);
+        let (command_sender, command_receiver) = mpsc::channel(COMMAND_CHANNEL_CAPACITY);
+        let (seed_sender, seed_receiver) = mpsc::channel(COMMAND_CHANNEL_CAPACITY);
+        let session = Self {
+            client,
+            create_fuzzer_req,
+            cfg,
+            fuzzer_id,
+            registration: None,
+            rpc_timeout,
+            sampler,
+            feature_receiver,
+            command_sender,
+            seed_sender,
+            reported_features: HashSet::new(),
+            replayed_updates: Vec::new(),
+            num_replayed_features: 0,
+            pending_updates: VecDeque::new(),
+        };
+        (session, feature_sender, command_receiver, seed_receiver)
+    }
+
+    /// Runs until the feature sender is dropped. `first_attempt` is notified
+    /// once the first registration succeeds or fails, with whether the
+    /// collector accepted the fuzzer, even if it's still analyzing its CFG.
+    pub async fn run(mut self, first_attempt: oneshot::Sender<bool>) {
+        let mut first_attempt = Some(first_attempt);
+        let mut reconnect_delay = MIN_RECONNECT_DELAY;
+        loop {
+            let connected = self.connect().await;
+            let analyzing_cfg =
+                matches!(&connected, Err(status) if status.code() == Code::Unavailable);
+            if let Some(first_attempt) = first_attempt.take() {
+                let _ = first_attempt.send(connected.is_ok() || analyzing_cfg);
+            }
+            if analyzing_cfg {
+                if let SessionEnd::Closed = self.wait_reconnect(CFG_ANALYSIS_POLL_INTERVAL).await {
+                    return;
+                }
+                continue;
+            }
+            if let Ok((fuzzer_id, resumed)) = connected {
+                reconnect_delay = MIN_RECONNECT_DELAY;
+                if let SessionEnd::Closed = self.serve(fuzzer_id, resumed).await {
+                    return;
+                }
+                self.fuzzer_id.store(NO_FUZZER_ID, Ordering::SeqCst);
+            }
+            if let SessionEnd::Closed = self.wait_reconnect(reconnect_delay).await {
+                return;
+            }
+            reconnect_delay = (reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+    }
+
+    // Resumes the last registration if the collector still knows the fuzzer,
+    // so it keeps its ID and coverage. Returns the fuzzer ID and whether the
+    // registration was resumed.
+    async fn connect(&mut self) -> Result<(u64, bool), Status> {
+        if let Some((fuzzer_id, collector_instance_id)) = self.registration {
+            let mut client = self.client.clone();
+            match with_timeout(
+                self.rpc_timeout,
+                client.heartbeat(HeartbeatRequest { id: fuzzer_id }),
+            )
+            .await
+            {
+                Ok(res) if res.get_ref().collector_instance_id == collector_instance_id => {
+                    self.fuzzer_id.store(fuzzer_id, Ordering::SeqCst);
+                    return Ok((fuzzer_id, true));
+                }
+                // The collector restarted, or removed the fuzzer meanwhile.
+                Ok(_) => {}
+                Err(status) if status.code() == Code::NotFound => {}
+                Err(status) => return Err(status),
+            }
+        }
+        self.register().await.map(|fuzzer_id| (fuzzer_id, false))
+    }
+
+    async fn register(&mut self) -> Result<u64, Status> {
+        let mut client = self.client.clone();
+        // Only upload the CFG if the collector doesn't have it yet.
+        let create_fuzzer_res = match with_timeout(
+            self.rpc_timeout,
+            client.create_fuzzer(self.create_fuzzer_req.clone()),
+        )
+        .await
+        {
+            Err(status) if status.code() == Code::NotFound => {
+                // Sending a large CFG can take longer than the RPC timeout. The
+                // collector doesn't wait for the analysis to answer, so the
+                // fuzzer is only created once it's done.
+                self.create_fuzzer_req.cfg_hash = upload_cfg(&mut client, &self.cfg).await?;
+                with_timeout(
+                    self.rpc_timeout,
+                    client.create_fuzzer(self.create_fuzzer_req.clone()),
+                )
+                .await
+            }
+            create_fuzzer_res => create_fuzzer_res,
+        };
+        let create_fuzzer_res = create_fuzzer_res?.into_inner();
+        let fuzzer_id = create_fuzzer_res.id;
+        self.registration = Some((fuzzer_id, create_fuzzer_res.collector_instance_id));
+        self.fuzzer_id.store(fuzzer_id, Ordering::SeqCst);
+        Ok(fuzzer_id)
+    }
+
+    // Forwards the feature updates until the fuzzer exits or the connection
+    // breaks. A resumed registration still has the coverage sent before, so
+    // only the pending updates are sent first.
+    async fn serve(&mut self, fuzzer_id: u64, resumed: bool) -> SessionEnd {
+        let (stream_sender, stream_receiver) = mpsc::channel(FEATURE_STREAM_CAPACITY);
+        let mut feature_stream = tokio::spawn(stream_features(
+            self.client.clone(),
+            stream_receiver,
+            self.sampler,
+        ));
+        let mut control = tokio::spawn(forward_commands(
+            self.client.clone(),
+            fuzzer_id,
+            self.command_sender.clone(),
+            self.seed_sender.clone(),
+        ));
+        let collector_instance_id = self.registration.map_or(0, |(_, instance_id)| instance_id);
+        let mut heartbeat = tokio::spawn(heartbeat(
+            self.client.clone(),
+            fuzzer_id,
+            collector_instance_id,
+            self.rpc_timeout,
+        ));
+
+        let mut backlog = self.take_backlog(resumed);
+        let mut feature_encoder = FeatureEncoder::default();
+        let end = loop {
+            let req = match backlog.pop_front() {
+                Some(req) => Some(req),
+                None => tokio::select! {
+                    req = self.feature_receiver.recv() => req,
+                    _ = &mut feature_stream => break SessionEnd::Disconnected,
+                    _ = &mut control => break SessionEnd::Disconnected,
+                    _ = &mut heartbeat => break SessionEnd::Disconnected,
+                },
+            };
+            let mut req = match req {
+                Some(req) => req,
+                None => break SessionEnd::Closed,
+            };
+            self.keep_for_replay(&req);
+            req.id = fuzzer_id;
+            feature_encoder.encode(&mut req);
+            if let Err(mpsc::error::SendError(mut req)) = stream_sender.send(req).await {
+                feature_encoder.restore_last(&mut req);
+                self.buffer(req);
+                break SessionEnd::Disconnected;
+            }
+        };
+        control.abort();
+        heartbeat.abort();
+        match end {
+            SessionEnd::Closed => {
+                // Wait until the pending updates are sent.
+                drop(stream_sender);
+                let _ = feature_stream.await;
+            }
+            SessionEnd::Disconnected => {
+                feature_stream.abort();
+                for req in backlog {
+                    self.buffer(req);
+                }
+            }
+        }
+        end
+    }
+
+    // Returns the updates to send before the new ones. A restarted collector
+    // gets the coverage replayed, and only the pending seeds on top of it.
+    fn take_backlog(&mut self, resumed: bool) -> VecDeque<UpdateFeaturesRequest> {
+        let mut backlog = VecDeque::new();
+        if resumed {
+            backlog.extend(self.pending_updates.drain(..));
+        } else {
+            backlog.extend(
+                self.replayed_updates
+                    .iter()
+                    .map(|features| UpdateFeaturesRequest {
+                        features: features.clone(),
+                        ..Default::default()
+                    }),
+            );
+            // The features of the other pending updates are replayed above.
+            backlog.extend(
+                self.pending_updates
+                    .drain(..)
+                    .filter(|req| !req.seed_sha1.is_empty()),
+            );
+        }
+        backlog
+    }
+
+    async fn wait_reconnect(&mut self, delay: Duration) -> SessionEnd {
+        let reconnect = time::sleep(delay);
+        tokio::pin!(reconnect);
+        loop {
+            tokio::select! {
+                _ = &mut reconnect => return SessionEnd::Disconnected,
+                req = self.feature_receiver.recv() => match req {
+                    Some(req) => self.buffer(req),
+                    None => return SessionEnd::Closed,
+                },
+            }
+        }
+    }
+
+    // Keeps the features of the update for a restarted collector if any of
+    // them is new. Returns whether there is a new feature.
+    fn keep_for_replay(&mut self, req: &UpdateFeaturesRequest) -> bool {
+        let mut new_feature = false;
+        for feature in req.features.iter() {
+            new_feature |= self.reported_features.insert(*feature);
+        }
+        if new_feature && self.num_replayed_features + req.features.len() <= MAX_REPLAYED_FEATURES {
+            self.num_replayed_features += req.features.len();
+            self.replayed_updates.push(req.features.clone());
+        }
+        new_feature
+    }
+
+    // Keeps an update which couldn't be sent if it has a seed or new features.
+    fn buffer(&mut self, req: UpdateFeaturesRequest) {
+        if !self.keep_for_replay(&req) && req.seed_sha1.is_empty() {
+            return;
+        }
+        if self.pending_updates.len() == MAX_PENDING_UPDATES {
+            self.pending_updates.pop_front();
+        }
+        self.pending_updates.push_back(req);
+    }
+}
+
+/// Uploads the CFG in chunks of functions and returns its hash.
+async fn upload_cfg(client: &mut ServiceClient, cfg: &ControlFlowGraph) -> Result<Vec<u8>, Status> {
+    let mut chunks = Vec::new();
+    let mut chunk = UploadCfgRequest::default();
+    let mut chunk_size = 0;
+    for function in cfg.functions.iter() {
+        let function_size = function.encoded_len();
+        if !chunk.functions.is_empty() && chunk_size + function_size > CFG_CHUNK_SIZE {
+            chunks.push(mem::take(&mut chunk));
+            chunk_size = 0;
+        }
+        chunk_size += function_size;
+        chunk.functions.push(function.clone());
+    }
+    chunks.push(chunk);
+    Ok(client
+        .upload_cfg(tokio_stream::iter(chunks))
+        .await?
+        .into_inner()
+        .cfg_hash)
+}
+
+// Returns once the feature stream is closed, after the pending updates are sent
+// if it's closed by the fuzzer.
+async fn stream_features(
+    mut client: ServiceClient,
+    stream_receiver: mpsc::Receiver<UpdateFeaturesRequest>,
+    sampler: &'static Sampler,
+) {
+    let mut responses = match client
+        .stream_features(ReceiverStream::new(stream_receiver))
+        .await
+    {
+        Ok(res) => res.into_inner(),
+        Err(_) => return,
+    };
+    while let Ok(Some(update_features_res)) = responses.message().await {
+        if update_features_res.sample_interval != 0 {
+            sampler.set_interval(update_features_res.sample_interval);
+        }
+    }
+}
+
+// Returns once the control stream is closed.
+async fn forward_commands(
+    mut client: ServiceClient,
+    fuzzer_id: u64,
+    command_sender: mpsc::Sender<ControlCommand>,
+    seed_sender: mpsc::Sender<Vec<u8>>,
+) {
+    // The sender is held to keep the outgoing half of the control stream open.
+    let (_control_sender, mut commands) = loop {
+        let (control_sender, control_receiver) = mpsc::channel(1);
+        control_sender
+            .try_send(ControlRequest { id: fuzzer_id })
+            .unwrap();
+        match client.control(ReceiverStream::new(control_receiver)).await {
+            Ok(res) => break (control_sender, res.into_inner()),
+            // The collector may not have noticed yet that the stream of a
+            // resumed registration is gone.
+            Err(status) if status.code() == Code::AlreadyExists => {
+                time::sleep(MIN_RECONNECT_DELAY).await
+            }
+            Err(_) => return,
+        }
+    };
+    while let Ok(Some(command)) = commands.message().await {
+        // Seeds are pulled separately from the other commands.
+        match command.command {
+            Some(Command::AddSeeds(add_seeds)) => {
+                for seed in add_seeds.seeds {
+                    let _ = seed_sender.try_send(seed);
+                }
+            }
+            _ => {
+                let _ = command_sender.try_send(command);
+            }
+        }
+    }
+}
+
+// Returns once the collector no longer knows the fuzzer, e.g. after a restart,
+// or stops responding.
+async fn heartbeat(
+    mut client: ServiceClient,
+    fuzzer_id: u64,
+    collector_instance_id: u64,
+    rpc_timeout: Duration,
+) {
+    let mut interval = time::interval(HEARTBEAT_INTERVAL);
+    let mut missed_heartbeats = 0;
+    loop {
+        interval.tick().await;
+        match with_timeout(
+            rpc_timeout,
+            client.heartbeat(HeartbeatRequest { id: fuzzer_id }),
+        )
+        .await
+        {
+            Ok(res) if res.get_ref().collector_instance_id == collector_instance_id => {
+                missed_heartbeats = 0;
+            }
+            Ok(_) => return,
+            Err(status) if status.code() == Code::NotFound => return,
+            // A missed heartbeat is fine as long as a later one gets through.
+            Err(_) => {
+                missed_heartbeats += 1;
+                if missed_heartbeats == MAX_MISSED_HEARTBEATS {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use collector_service::{create_service, BitCounter, Controller, Observer};
+    use common::{
+        collector_proto::control_flow_graph::{BasicBlock, Function},
+        hash_cfg,
+        observer_proto::{Feature, FuzzerMetadata, StructureGraph},
+        NO_SANCOV_INDEX,
+    };
+    use std::{net::SocketAddr, sync::Mutex, thread, time::Instant};
+    use tokio::{net::TcpListener, runtime};
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::transport::{Endpoint, Server};
+
+    #[derive(Debug, PartialEq, Eq)]
+    enum ObservedCall {
+        CreateFuzzer { num_nodes: usize },
+        UpdateFeatures { nodes: Vec<usize> },
+    }
+
+    struct TestObserver {
+        calls: Arc<Mutex<Vec<ObservedCall>>>,
+    }
+
+    #[async_trait]
+    impl Observer for TestObserver {
+        async fn create_fuzzer(
+            &self,
+            _fuzzer_id: u64,
+            _metadata: &FuzzerMetadata,
+            struct_graph: &StructureGraph,
+        ) {
+            self.calls.lock().unwrap().push(ObservedCall::CreateFuzzer {
+                num_nodes: struct_graph.nodes.len(),
+            });
+        }
+
+        async fn update_features(
+            &self,
+            _fuzzer_id: u64,
+            bit_counters: &[(usize, BitCounter)],
+            _features: &[Feature],
+        ) {
+            let mut nodes: Vec<usize> = bit_counters.iter().map(|(node, _)| *node).collect();
+            nodes.sort_unstable();
+            self.calls
+                .lock()
+                .unwrap()
+                .push(ObservedCall::UpdateFeatures { nodes });
+        }
+    }
+
+    // Runs on a runtime of its own, so dropping it closes every connection as
+    // if the collector crashed.
+    struct TestCollector {
+        addr: SocketAddr,
+        calls: Arc<Mutex<Vec<ObservedCall>>>,
+        _runtime: runtime::Runtime,
+    }
+
+    impl TestCollector {
+        fn start(addr: SocketAddr) -> Self {
+            let runtime = runtime::Builder::new_multi_thread()
+                .worker_threads(1)
+                .enable_all()
+                .build()
+                .unwrap();
+            let listener = runtime.block_on(TcpListener::bind(addr)).unwrap();
+            let addr = listener.local_addr().unwrap();
+            let calls = Arc::new(Mutex::new(Vec::new()));
+            let observer = Box::new(TestObserver {
+                calls: calls.clone(),
+            });
+            runtime.spawn(async move {
+                Server::builder()
+                    .add_service(create_service(observer, Controller::new()))
+                    .serve_with_incoming(TcpListenerStream::new(listener))
+                    .await
+            });
+            Self {
+                addr,
+                calls,
+                _runtime: runtime,
+            }
+        }
+
+        // Waits until the collector has observed `num_calls` calls.
+        fn wait_for_calls(&self, num_calls: usize) -> Vec<ObservedCall> {
+            let deadline = Instant::now() + Duration::from_secs(30);
+            while self.calls.lock().unwrap().len() < num_calls {
+                assert!(
+                    Instant::now() < deadline,
+                    "Collector wasn't called in time."
+                );
+                thread::sleep(Duration::from_millis(10));
+            }
+            mem::take(&mut *self.calls.lock().unwrap())
+        }
+    }
+
+    // Block 1 is only reached through the uninstrumented block 2.
+    fn build_cfg() -> ControlFlowGraph {
+        let block = |id, successors: Vec<u64>, sancov_index| BasicBlock {
+            id,
+            successors,
+            sancov_index,
+        };
+        ControlFlowGraph {
+            functions: vec![Function {
+                id: 0,
+                name: "main".to_owned(),
+                basic_blocks: vec![
+                    block(0, vec![2], 0),
+                    block(1, vec![], 1),
+                    block(2, vec![1], NO_SANCOV_INDEX),
+                ],
+            }],
+        }
+    }
+
+    fn new_session(
+        runtime: &runtime::Runtime,
+        addr: SocketAddr,
+    ) -> (Session, mpsc::Sender<UpdateFeaturesRequest>) {
+        let channel = {
+            let _guard = runtime.enter();
+            Endpoint::from_shared(format!("http://{}", addr))
+                .unwrap()
+                .connect_lazy()
+                .unwrap()
+        };
+        let cfg = build_cfg();
+        let create_fuzzer_req = CreateFuzzerRequest {
+            cfg_hash: hash_cfg(&cfg),
+            ..Default::default()
+        };
+        let (session, feature_sender, _, _) = Session::new(
+            ServiceClient::new(channel),
+            create_fuzzer_req,
+            cfg,
+            Arc::new(AtomicU64::new(NO_FUZZER_ID)),
+            Duration::from_secs(10),
+            Box::leak(Box::new(Sampler::new(0))),
+        );
+        (session, feature_sender)
+    }
+
+    fn update(features: &[u32], seed_sha1: &[u8]) -> UpdateFeaturesRequest {
+        UpdateFeaturesRequest {
+            features: features.to_vec(),
+            seed_sha1: seed_sha1.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    fn backlog_features(backlog: VecDeque<UpdateFeaturesRequest>) -> Vec<Vec<u32>> {
+        backlog.into_iter().map(|req| req.features).collect()
+    }
+
+    #[test]
+    fn buffers_seeds_and_new_features() {
+        let runtime = runtime::Runtime::new().unwrap();
+        let (mut session, _) = new_session(&runtime, "127.0.0.1:1".parse().unwrap());
+        session.buffer(update(&[1, 2], &[]));
+        session.buffer(update(&[2, 1], &[]));
+        session.buffer(update(&[1], &[0; 20]));
+        session.buffer(update(&[2, 3], &[]));
+        assert_eq!(
+            backlog_features(session.take_backlog(true)),
+            [vec![1, 2], vec![1], vec![2, 3]]
+        );
+        assert!(session.take_backlog(true).is_empty());
+    }
+
+    #[test]
+    fn backlog_after_restart_replays_coverage() {
+        let runtime = runtime::Runtime::new().unwrap();
+        let (mut session, _) = new_session(&runtime, "127.0.0.1:1".parse().unwrap());
+        // Sent before the collector restarted.
+        session.keep_for_replay(&update(&[1, 2], &[]));
+        session.keep_for_replay(&update(&[1], &[]));
+        // Couldn't be sent.
+        session.buffer(update(&[3], &[]));
+        session.buffer(update(&[1], &[0; 20]));
+        assert_eq!(
+            backlog_features(session.take_backlog(false)),
+            [vec![1, 2], vec![3], vec![1]]
+        );
+    }
+
+    #[test]
+    fn drops_oldest_pending_updates() {
+        let runtime = runtime::Runtime::new().unwrap();
+        let (mut session, _) = new_session(&runtime, "127.0.0.1:1".parse().unwrap());
+        for feature in 0..MAX_PENDING_UPDATES as u32 + 2 {
+            session.buffer(update(&[feature], &[]));
+        }
+        let backlog = session.take_backlog(true);
+        assert_eq!(backlog.len(), MAX_PENDING_UPDATES);
+        assert_eq!(backlog[0].features, [2]);
+        // The coverage of the dropped updates is still replayed.
+        assert_eq!(session.replayed_updates.len(), MAX_PENDING_UPDATES + 2);
+    }
+
+    #[test]
+    fn replays_coverage_to_restarted_collector() {
+        let collector = TestCollector::start("127.0.0.1:0".parse().unwrap());
+        let addr = collector.addr;
+        let runtime = runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let (session, feature_sender) = new_session(&runtime, addr);
+        let (first_attempt_sender, first_attempt_receiver) = oneshot::channel();
+        let session_handle = runtime.spawn(session.run(first_attempt_sender));
+        assert!(runtime.block_on(first_attempt_receiver).unwrap());
+
+        runtime
+            .block_on(feature_sender.send(update(&[0, 8], &[])))
+            .unwrap();
+        assert_eq!(
+            collector.wait_for_calls(2),
+            [
+                ObservedCall::CreateFuzzer { num_nodes: 3 },
+                ObservedCall::UpdateFeatures {
+                    nodes: vec![0, 1, 2]
+                },
+            ]
+        );
+
+        // The restarted collector has lost the fuzzer, its CFG and coverage.
+        drop(collector);
+        let collector = TestCollector::start(addr);
+        assert_eq!(
+            collector.wait_for_calls(2),
+            [
+                ObservedCall::CreateFuzzer { num_nodes: 3 },
+                ObservedCall::UpdateFeatures {
+                    nodes: vec![0, 1, 2]
+                },
+            ]
+        );
+        // New updates follow the replayed ones.
+        runtime
+            .block_on(feature_sender.send(update(&[0], &[])))
+            .unwrap();
+        assert_eq!(
+            collector.wait_for_calls(1),
+            [ObservedCall::UpdateFeatures { nodes: vec![] }]
+        );
+
+        drop(feature_sender);
+        runtime.block_on(session_handle).unwrap();
+    }
+}
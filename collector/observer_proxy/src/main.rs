@@ -14,9 +14,13 @@
 
 use async_trait::async_trait;
 use clap::Arg;
+use collector_service::{BitCounter, RemoveReason};
 use common::observer_proto::{
-    observer_service_client::ObserverServiceClient, update_features_request::BitCounter,
-    CreateFuzzerRequest, StructureGraph, UpdateFeaturesRequest,
+    observer_service_client::ObserverServiceClient, remove_fuzzer_request::Reason,
+    update_edges_request::EdgeBitCounter,
+    update_features_request::BitCounter as ObserverBitCounter, Crash, CreateFuzzerRequest, Feature,
+    FuzzerMetadata, FuzzerStats, RemoveFuzzerRequest, ReportCrashRequest, StructureGraph,
+    UpdateEdgesRequest, UpdateFeaturesRequest, UpdateStatsRequest,
 };
 use tokio::sync::Mutex;
 use tonic::transport::Server;
@@ -27,27 +31,86 @@ struct Proxy {
 
 #[async_trait]
 impl collector_service::Observer for Proxy {
-    async fn create_fuzzer(&self, fuzzer_id: u64, struct_graph: &StructureGraph) {
+    async fn create_fuzzer(
+        &self,
+        fuzzer_id: u64,
+        metadata: &FuzzerMetadata,
+        struct_graph: &StructureGraph,
+    ) {
         let req = CreateFuzzerRequest {
             fuzzer_id,
             structure_graph: Some(struct_graph.clone()),
+            metadata: Some(metadata.clone()),
         };
         self.client.lock().await.create_fuzzer(req).await.unwrap();
     }
 
-    async fn update_features(&self, fuzzer_id: u64, bit_counters: &[(usize, u8)]) {
+    async fn update_features(
+        &self,
+        fuzzer_id: u64,
+        bit_counters: &[(usize, BitCounter)],
+        features: &[Feature],
+    ) {
         let req = UpdateFeaturesRequest {
             fuzzer_id,
             bit_counters: bit_counters
                 .iter()
-                .map(|&(node_index, counter)| BitCounter {
+                .map(|&(node_index, counter)| ObserverBitCounter {
                     node_index: node_index as u64,
-                    counter: counter as u32,
+                    counter: counter.bits() as u32,
+                    max_bucket: counter.max_bucket().map_or(0, |bucket| bucket as i32),
                 })
                 .collect(),
+            features: features.to_vec(),
         };
         self.client.lock().await.update_features(req).await.unwrap();
     }
+
+    async fn update_edges(&self, fuzzer_id: u64, edge_bit_counters: &[(usize, usize, BitCounter)]) {
+        let req = UpdateEdgesRequest {
+            fuzzer_id,
+            edge_bit_counters: edge_bit_counters
+                .iter()
+                .map(
+                    |&(src_node_index, dst_node_index, counter)| EdgeBitCounter {
+                        src_node_index: src_node_index as u64,
+                        dst_node_index: dst_node_index as u64,
+                        counter: counter.bits() as u32,
+                        max_bucket: counter.max_bucket().map_or(0, |bucket| bucket as i32),
+                    },
+                )
+                .collect(),
+        };
+        self.client.lock().await.update_edges(req).await.unwrap();
+    }
+
+    async fn update_stats(&self, fuzzer_id: u64, stats: &FuzzerStats) {
+        let req = UpdateStatsRequest {
+            fuzzer_id,
+            stats: Some(stats.clone()),
+        };
+        self.client.lock().await.update_stats(req).await.unwrap();
+    }
+
+    async fn remove_fuzzer(&self, fuzzer_id: u64, reason: RemoveReason) {
+        let reason = match reason {
+            RemoveReason::Deleted => Reason::Deleted,
+            RemoveReason::Expired => Reason::Expired,
+        };
+        let req = RemoveFuzzerRequest {
+            fuzzer_id,
+            reason: reason as i32,
+        };
+        self.client.lock().await.remove_fuzzer(req).await.unwrap();
+    }
+
+    async fn report_crash(&self, fuzzer_id: u64, crash: &Crash) {
+        let req = ReportCrashRequest {
+            fuzzer_id,
+            crash: Some(crash.clone()),
+        };
+        self.client.lock().await.report_crash(req).await.unwrap();
+    }
 }
 
 #[tokio::main]
@@ -85,7 +148,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .unwrap();
     println!("Observer Proxy listening on {}.", addr);
     Server::builder()
-        .add_service(collector_service::create_service(observer_ptr))
+        .add_service(collector_service::create_service(
+            observer_ptr,
+            collector_service::Controller::new(),
+        ))
         .serve(addr)
         .await?;
     Ok(())
@@ -40,6 +40,60 @@ struct Module {
 struct FuzzerClientParam {
   Module *Modules;
   size_t ModulesSize;
+  // Index of the job when running with -jobs, -1 otherwise.
+  int32_t JobIndex;
+  // Layout of the features passed to fuzzer_client_update_features, see
+  // TracePC::CollectFeatures.
+  uint64_t CountersSize;
+  uint64_t ValueProfileSize;
+};
+
+enum CommandKind {
+  kCommandNone = 0,
+  kCommandStop = 1,
+  kCommandSetSeedPriorities = 2,
+};
+
+struct SeedPriority {
+  uint8_t Sha1[20];
+  // Relative to the default of 1. Passed on as sent by the collector, and
+  // clamped by InputCorpus::SetCollectorPriorities.
+  double Energy;
+};
+
+struct Command {
+  CommandKind Kind;
+  // Valid for kCommandSetSeedPriorities until the next poll.
+  const SeedPriority *SeedPriorities;
+  size_t SeedPrioritiesSize;
+};
+
+struct Stats {
+  uint64_t ExecsPerSec;
+  uint64_t TotalExecs;
+  uint64_t CorpusSize;
+  uint64_t CorpusBytes;
+  uint64_t PeakRssMb;
+  uint64_t SlowestUnitTimeSec;
+  uint64_t Timeouts;
+  uint64_t Ooms;
+};
+
+struct Crash {
+  // "crash", "timeout", "oom" or "leak".
+  const char *Kind;
+  const uint8_t *Input;
+  size_t InputSize;
+  // Sanitizer report, null if there is none.
+  const char *Report;
+  // Symbolized function names, innermost first.
+  const char *const *StackFrames;
+  size_t StackFramesSize;
+};
+
+struct Seed {
+  const uint8_t *Data;
+  size_t Size;
 };
 
 } // namespace fuzzer_client
@@ -47,7 +101,30 @@ struct FuzzerClientParam {
 extern "C" void
 fuzzer_client_init(const fuzzer_client::FuzzerClientParam *Param);
 
+// SeedSha1 and Seed are null unless the features come from a newly added
+// seed, which is then uploaded to the collector's corpus.
 extern "C" void fuzzer_client_update_features(const uint32_t *Features,
-                                              size_t FeaturesSize);
+                                              size_t FeaturesSize,
+                                              const uint8_t *SeedSha1,
+                                              const uint8_t *Seed,
+                                              size_t SeedSize);
+
+// Whether the current execution reports its full feature set even without new
+// coverage. The sampling rate is configured in the client.
+extern "C" bool fuzzer_client_should_sample();
+
+// Flushes pending updates and deletes the fuzzer from the collector.
+extern "C" void fuzzer_client_fini();
+
+extern "C" bool fuzzer_client_poll_command(fuzzer_client::Command *Command);
+
+extern "C" void fuzzer_client_update_stats(const fuzzer_client::Stats *Stats);
+
+// Blocks until the collector has received the crash.
+extern "C" void fuzzer_client_report_crash(const fuzzer_client::Crash *Crash);
+
+// Pops the next seed found by another fuzzer of the same target. Returns false
+// if there is none. The seed data is valid until the next pull.
+extern "C" bool fuzzer_client_pull_seed(fuzzer_client::Seed *Seed);
 
 #endif // FUZZER_CLIENT_H_
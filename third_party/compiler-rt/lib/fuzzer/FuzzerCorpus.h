@@ -18,8 +18,10 @@
 #include "FuzzerSHA1.h"
 #include "FuzzerTracePC.h"
 #include <algorithm>
+#include <cmath>
 #include <numeric>
 #include <random>
+#include <unordered_map>
 #include <unordered_set>
 
 namespace fuzzer {
@@ -43,6 +45,8 @@ struct InputInfo {
   double Energy = 0.0;
   size_t SumIncidence = 0;
   Vector<std::pair<uint32_t, uint16_t>> FeatureFreqs;
+  // Weight multiplier pushed by the collector.
+  double CollectorPriority = 1.0;
 
   // Delete feature Idx and its frequency from FeatureFreqs.
   bool DeleteFeatureFreq(uint32_t Idx) {
@@ -260,6 +264,22 @@ public:
     DistributionNeedsUpdate = true;
   }
 
+  // Priorities are keyed by the SHA1 string of the seed. They're clamped to
+  // finite non-negative weights, ignoring NaNs, as a single bad one would
+  // break the whole distribution.
+  void SetCollectorPriorities(
+      const std::unordered_map<std::string, double> &Priorities) {
+    static const double kMaxCollectorPriority = 1e6;
+    for (auto II : Inputs) {
+      auto It = Priorities.find(Sha1ToString(II->Sha1));
+      if (It == Priorities.end() || std::isnan(It->second))
+        continue;
+      II->CollectorPriority =
+          std::min(std::max(It->second, 0.0), kMaxCollectorPriority);
+    }
+    DistributionNeedsUpdate = true;
+  }
+
   bool HasUnit(const Unit &U) { return Hashes.count(Hash(U)); }
   bool HasUnit(const std::string &H) { return Hashes.count(H); }
   InputInfo &ChooseUnitToMutate(Random &Rand) {
@@ -496,6 +516,16 @@ private:
                          : 0.;
     }
 
+    // Apply the collector priorities unless they would zero out every seed,
+    // e.g. if all of them are zero, in which case the weights are kept.
+    bool HasPrioritizedWeight = false;
+    for (size_t i = 0; i < N; i++)
+      if (Weights[i] * Inputs[i]->CollectorPriority > 0.0)
+        HasPrioritizedWeight = true;
+    if (HasPrioritizedWeight)
+      for (size_t i = 0; i < N; i++)
+        Weights[i] *= Inputs[i]->CollectorPriority;
+
     if (FeatureDebug) {
       for (size_t i = 0; i < N; i++)
         Printf("%zd ", Inputs[i]->NumFeatures);
@@ -233,6 +233,7 @@ static void WorkerThread(const Command &BaseCmd, std::atomic<unsigned> *Counter,
     if (C >= NumJobs) break;
     std::string Log = "fuzz-" + std::to_string(C) + ".log";
     Command Cmd(BaseCmd);
+    Cmd.addFlag("fuzvisor_job_index", std::to_string(C));
     Cmd.setOutputFile(Log);
     Cmd.combineOutAndErr();
     if (Flags.verbosity) {
@@ -610,9 +611,12 @@ static Vector<SizedFile> ReadCorpora(const Vector<std::string> &CorpusDirs,
 
 void CallFuzzerClientInit() {
   auto Modules = TPC.GetFuzzerClientModules();
-  const fuzzer_client::FuzzerClientParam Param = {Modules.data(),
-                                                  Modules.size()};
+  const fuzzer_client::FuzzerClientParam Param = {
+      Modules.data(), Modules.size(), Flags.fuzvisor_job_index,
+      TPC.NumCounterFeatures(),
+      Flags.use_value_profile ? ValueBitMap::kMapSizeInBits : 0};
   fuzzer_client_init(&Param);
+  std::atexit(fuzzer_client_fini);
 }
 
 int FuzzerDriver(int *argc, char ***argv, UserCallback Callback) {
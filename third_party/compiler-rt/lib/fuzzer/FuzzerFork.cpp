@@ -372,6 +372,18 @@ void FuzzWithFork(Random &Rand, const FuzzingOptions &Options,
       }
     }
 
+    // Only the totals over all jobs are known here.
+    size_t Seconds = Env.secondsSinceProcessStartUp();
+    const fuzzer_client::Stats Stats = {Seconds ? Env.NumRuns / Seconds : 0,
+                                        Env.NumRuns,
+                                        Env.Files.size(),
+                                        0,
+                                        0,
+                                        0,
+                                        Env.NumTimeouts,
+                                        Env.NumOOMs};
+    fuzzer_client_update_stats(&Stats);
+
     // Stop if we are over the time budget.
     // This is not precise, since other threads are still running
     // and we will wait while joining them.
@@ -25,7 +25,6 @@
 #include <chrono>
 #include <climits>
 #include <cstdlib>
-#include <random>
 #include <string.h>
 
 namespace fuzzer {
@@ -105,9 +104,15 @@ private:
                   size_t Features = 0);
   void PrintStatusForNewUnit(const Unit &U, const char *Text);
   void CheckExitOnSrcPosOrItem();
+  bool HandleFuzzerClientCommands();
+  void RunFuzzerClientSeeds();
+  void UpdateFuzzerClientStats();
+  void ReportCrashToFuzzerClient(const char *Prefix, const uint8_t *Data,
+                                 size_t Size, bool WithStack);
 
   static void StaticDeathCallback();
-  void DumpCurrentUnit(const char *Prefix);
+  // WithStack tells whether the current stack leads to the bug.
+  void DumpCurrentUnit(const char *Prefix, bool WithStack = true);
   void DeathCallback();
 
   void AllocateCurrentUnitData();
@@ -145,8 +150,6 @@ private:
   Vector<uint32_t> UniqFeatureSetTmp;
   Vector<uint32_t> FullFeatureSetTmp;
 
-  std::mt19937 FuzzerClientSampleRng = std::mt19937(23);
-
   // Need to know our own thread.
   static thread_local bool IsMyThread;
 };
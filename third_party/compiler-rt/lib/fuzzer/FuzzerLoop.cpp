@@ -23,6 +23,10 @@
 #include <mutex>
 #include <set>
 
+#if LIBFUZZER_LINUX || LIBFUZZER_APPLE
+#include <execinfo.h>
+#endif
+
 #if defined(__has_include)
 #if __has_include(<sanitizer / lsan_interface.h>)
 #include <sanitizer/lsan_interface.h>
@@ -47,6 +51,17 @@ bool RunningUserCallback = false;
 // Only one Fuzzer per process.
 static Fuzzer *F;
 
+static const int kMaxCrashStackFrames = 64;
+static const int kFuzzerClientStatsIntervalSec = 10;
+static const size_t kMaxFuzzerClientSeedsPerLoop = 16;
+
+// Report of the last sanitizer error, sent to the collector with the crash.
+static std::string SanitizerReport;
+
+static void ErrorReportCallback(const char *Report) {
+  SanitizerReport = Report;
+}
+
 // Leak detection is expensive, so we first check if there were more mallocs
 // than frees (using the sanitizer malloc hooks) and only then try to call lsan.
 struct MallocFreeTracer {
@@ -142,6 +157,8 @@ Fuzzer::Fuzzer(UserCallback CB, InputCorpus &Corpus, MutationDispatcher &MD,
     : CB(CB), Corpus(Corpus), MD(MD), Options(Options) {
   if (EF->__sanitizer_set_death_callback)
     EF->__sanitizer_set_death_callback(StaticDeathCallback);
+  if (EF->__asan_set_error_report_callback)
+    EF->__asan_set_error_report_callback(ErrorReportCallback);
   assert(!F);
   F = this;
   TPC.ResetMaps();
@@ -175,7 +192,7 @@ void Fuzzer::StaticDeathCallback() {
   F->DeathCallback();
 }
 
-void Fuzzer::DumpCurrentUnit(const char *Prefix) {
+void Fuzzer::DumpCurrentUnit(const char *Prefix, bool WithStack) {
   if (!CurrentUnitData)
     return; // Happens when running individual inputs.
   ScopedDisableMsanInterceptorChecks S;
@@ -188,6 +205,51 @@ void Fuzzer::DumpCurrentUnit(const char *Prefix) {
   }
   WriteUnitToFileWithPrefix({CurrentUnitData, CurrentUnitData + UnitSize},
                             Prefix);
+  ReportCrashToFuzzerClient(Prefix, CurrentUnitData, UnitSize, WithStack);
+}
+
+// Symbolizes the current stack. It includes libFuzzer and sanitizer frames,
+// which the collector skips.
+static std::vector<std::string> GetSymbolizedStackFrames() {
+  std::vector<std::string> Frames;
+#if LIBFUZZER_LINUX || LIBFUZZER_APPLE
+  if (!EF->__sanitizer_symbolize_pc)
+    return Frames;
+  void *PCs[kMaxCrashStackFrames];
+  int NumPCs = backtrace(PCs, kMaxCrashStackFrames);
+  char FunctionName[1024];
+  for (int I = 0; I < NumPCs; I++) {
+    // Step back from the return address into the call instruction.
+    void *PC =
+        reinterpret_cast<void *>(reinterpret_cast<uintptr_t>(PCs[I]) - 1);
+    EF->__sanitizer_symbolize_pc(PC, "%f", FunctionName, sizeof(FunctionName));
+    Frames.push_back(FunctionName);
+  }
+#endif
+  return Frames;
+}
+
+void Fuzzer::ReportCrashToFuzzerClient(const char *Prefix, const uint8_t *Data,
+                                       size_t Size, bool WithStack) {
+  // The prefix of the crash file, e.g. "crash-".
+  std::string Kind(Prefix);
+  if (!Kind.empty() && Kind.back() == '-')
+    Kind.pop_back();
+  // Without frames, the collector doesn't deduplicate the crash.
+  std::vector<std::string> Frames;
+  if (WithStack)
+    Frames = GetSymbolizedStackFrames();
+  std::vector<const char *> FramePtrs;
+  for (auto &Frame : Frames)
+    FramePtrs.push_back(Frame.c_str());
+  const fuzzer_client::Crash Crash = {
+      Kind.c_str(),
+      Data,
+      Size,
+      SanitizerReport.empty() ? nullptr : SanitizerReport.c_str(),
+      FramePtrs.data(),
+      FramePtrs.size()};
+  fuzzer_client_report_crash(&Crash);
 }
 
 NO_SANITIZE_MEMORY
@@ -316,7 +378,8 @@ void Fuzzer::RssLimitCallback() {
       GetPid(), GetPeakRSSMb(), Options.RssLimitMb);
   Printf("   To change the out-of-memory limit use -rss_limit_mb=<N>\n\n");
   PrintMemoryProfile();
-  DumpCurrentUnit("oom-");
+  // Runs on the RSS thread, whose stack has nothing to do with the input.
+  DumpCurrentUnit("oom-", /*WithStack=*/false);
   Printf("SUMMARY: libFuzzer: out-of-memory\n");
   PrintFinalStats();
   _Exit(Options.OOMExitCode); // Stop right now.
@@ -490,13 +553,18 @@ bool Fuzzer::RunOne(const uint8_t *Data, size_t Size, bool MayDeleteFile,
     *FoundUniqFeatures = FoundUniqFeaturesOfII;
   PrintPulseAndReportSlowInput(Data, Size);
 
-  bool NeedToSample = !(FuzzerClientSampleRng() & (16384 - 1));
+  bool NeedToSample = fuzzer_client_should_sample();
   size_t NumNewFeatures = Corpus.NumFeatureUpdates() - NumUpdatesBefore;
   if (NeedToSample || NumNewFeatures) {
     TPC.CollectFeatures(
         [&](size_t Feature) { FullFeatureSetTmp.push_back(Feature); });
-    fuzzer_client_update_features(FullFeatureSetTmp.data(),
-                                  FullFeatureSetTmp.size());
+    uint8_t SeedSha1[kSHA1NumBytes];
+    if (NumNewFeatures)
+      ComputeSHA1(Data, Size, SeedSha1);
+    fuzzer_client_update_features(
+        FullFeatureSetTmp.data(), FullFeatureSetTmp.size(),
+        NumNewFeatures ? SeedSha1 : nullptr, NumNewFeatures ? Data : nullptr,
+        NumNewFeatures ? Size : 0);
   }
   if (NumNewFeatures) {
     TPC.UpdateObservedPCs();
@@ -665,7 +733,8 @@ void Fuzzer::TryDetectingAMemoryLeak(const uint8_t *Data, size_t Size,
       Printf("\nINFO: a leak has been found in the initial corpus.\n\n");
     Printf("INFO: to ignore leaks on libFuzzer side use -detect_leaks=0.\n\n");
     CurrentUnitSize = Size;
-    DumpCurrentUnit("leak-");
+    // The leaked allocations were made by the execution which just returned.
+    DumpCurrentUnit("leak-", /*WithStack=*/false);
     PrintFinalStats();
     _Exit(Options.ErrorExitCode); // not exit() to disable lsan further on.
   }
@@ -813,6 +882,7 @@ void Fuzzer::Loop(Vector<SizedFile> &CorporaFiles) {
   TPC.SetPrintNewPCs(Options.PrintNewCovPcs);
   TPC.SetPrintNewFuncs(Options.PrintNewCovFuncs);
   system_clock::time_point LastCorpusReload = system_clock::now();
+  system_clock::time_point LastFuzzerClientStatsUpdate = system_clock::now();
 
   TmpMaxMutationLen =
       Min(MaxMutationLen, Max(size_t(4), Corpus.MaxInputSize()));
@@ -831,6 +901,14 @@ void Fuzzer::Loop(Vector<SizedFile> &CorporaFiles) {
       break;
     if (TimedOut())
       break;
+    if (HandleFuzzerClientCommands())
+      break;
+    RunFuzzerClientSeeds();
+    if (duration_cast<seconds>(Now - LastFuzzerClientStatsUpdate).count() >=
+        kFuzzerClientStatsIntervalSec) {
+      UpdateFuzzerClientStats();
+      LastFuzzerClientStatsUpdate = Now;
+    }
 
     // Update TmpMaxMutationLen
     if (Options.LenControl) {
@@ -853,6 +931,65 @@ void Fuzzer::Loop(Vector<SizedFile> &CorporaFiles) {
 
   PrintStats("DONE  ", "\n");
   MD.PrintRecommendedDictionary();
+  UpdateFuzzerClientStats();
+}
+
+void Fuzzer::UpdateFuzzerClientStats() {
+  // Timeouts and OOMs end the process, so they are never counted here.
+  const fuzzer_client::Stats Stats = {execPerSec(),
+                                      TotalNumberOfRuns,
+                                      Corpus.NumActiveUnits(),
+                                      Corpus.SizeInBytes(),
+                                      GetPeakRSSMb(),
+                                      (uint64_t)TimeOfLongestUnitInSeconds,
+                                      0,
+                                      0};
+  fuzzer_client_update_stats(&Stats);
+}
+
+// Returns true if the collector asked the fuzzer to stop.
+bool Fuzzer::HandleFuzzerClientCommands() {
+  fuzzer_client::Command Command;
+  while (fuzzer_client_poll_command(&Command)) {
+    switch (Command.Kind) {
+    case fuzzer_client::kCommandStop:
+      Printf("INFO: stop requested by the collector\n");
+      return true;
+    case fuzzer_client::kCommandSetSeedPriorities: {
+      std::unordered_map<std::string, double> Priorities;
+      for (size_t i = 0; i < Command.SeedPrioritiesSize; i++) {
+        const fuzzer_client::SeedPriority &Priority = Command.SeedPriorities[i];
+        Priorities[Sha1ToString(Priority.Sha1)] = Priority.Energy;
+      }
+      Corpus.SetCollectorPriorities(Priorities);
+      break;
+    }
+    default:
+      break;
+    }
+  }
+  return false;
+}
+
+// Runs the seeds other fuzzers of the same target sent through the collector.
+void Fuzzer::RunFuzzerClientSeeds() {
+  fuzzer_client::Seed Seed;
+  bool Reloaded = false;
+  for (size_t i = 0; i < kMaxFuzzerClientSeedsPerLoop; i++) {
+    if (!fuzzer_client_pull_seed(&Seed))
+      break;
+    Unit U(Seed.Data, Seed.Data + Seed.Size);
+    if (U.size() > MaxInputLen)
+      U.resize(MaxInputLen);
+    if (!Corpus.HasUnit(U)) {
+      if (RunOne(U.data(), U.size())) {
+        CheckExitOnSrcPosOrItem();
+        Reloaded = true;
+      }
+    }
+  }
+  if (Reloaded)
+    PrintStats("SYNC  ");
 }
 
 void Fuzzer::MinimizeCrashLoop(const Unit &U) {
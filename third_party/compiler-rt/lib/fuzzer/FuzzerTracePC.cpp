@@ -62,6 +62,16 @@ std::vector<fuzzer_client::Module> TracePC::GetFuzzerClientModules() {
   return std::move(Modules);
 }
 
+// Number of features taken by the counters in CollectFeatures.
+size_t TracePC::NumCounterFeatures() const {
+  size_t NumCounters = ExtraCountersEnd() - ExtraCountersBegin();
+  for (size_t i = 0; i < NumModules; i++)
+    for (size_t r = 0; r < Modules[i].NumRegions; r++)
+      if (Modules[i].Regions[r].Enabled)
+        NumCounters += Modules[i].Regions[r].Stop - Modules[i].Regions[r].Start;
+  return 8 * NumCounters;
+}
+
 void TracePC::HandleCollectorInit(const uint8_t *CfgPayload,
                                   size_t CfgPayloadSize,
                                   const uint64_t *RemapStarts,
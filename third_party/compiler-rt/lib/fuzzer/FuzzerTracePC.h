@@ -90,6 +90,7 @@ class TracePC {
                            const uint8_t **RemapAddresses,
                            const uint64_t RemapsSize, const uint8_t *RemapBase);
   std::vector<fuzzer_client::Module> GetFuzzerClientModules();
+  size_t NumCounterFeatures() const;
 
   void ResetMaps() {
     ValueProfileMap.Reset();